@@ -22,6 +22,8 @@ pub enum Relation {
         on_delete = "Cascade"
     )]
     Program,
+    #[sea_orm(has_many = "super::reminder::Entity")]
+    Reminder,
 }
 
 impl Related<super::program::Entity> for Entity {
@@ -30,4 +32,10 @@ impl Related<super::program::Entity> for Entity {
     }
 }
 
+impl Related<super::reminder::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Reminder.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -4,4 +4,11 @@ pub mod prelude;
 
 pub mod entry;
 pub mod newsletter;
+pub mod newsletter_message;
+pub mod preference;
 pub mod program;
+pub mod reminder;
+pub mod subscription;
+pub mod template;
+pub mod tonight_message;
+pub mod watch;
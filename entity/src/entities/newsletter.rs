@@ -11,14 +11,25 @@ pub struct Model {
     pub link: String,
     pub message_id: Option<i32>,
     pub created_at: DateTimeWithTimeZone,
+    pub preview_message_id: Option<i32>,
+    pub publish_at: Option<DateTimeWithTimeZone>,
+    pub pinned_message_id: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::newsletter_message::Entity")]
+    NewsletterMessage,
     #[sea_orm(has_many = "super::program::Entity")]
     Program,
 }
 
+impl Related<super::newsletter_message::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::NewsletterMessage.def()
+    }
+}
+
 impl Related<super::program::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::Program.def()
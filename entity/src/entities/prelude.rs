@@ -2,4 +2,11 @@
 
 pub use super::entry::Entity as Entry;
 pub use super::newsletter::Entity as Newsletter;
+pub use super::newsletter_message::Entity as NewsletterMessage;
+pub use super::preference::Entity as Preference;
 pub use super::program::Entity as Program;
+pub use super::reminder::Entity as Reminder;
+pub use super::subscription::Entity as Subscription;
+pub use super::template::Entity as Template;
+pub use super::tonight_message::Entity as TonightMessage;
+pub use super::watch::Entity as Watch;
@@ -9,6 +9,8 @@ pub struct Model {
     pub id: i32,
     pub newsletter_id: i32,
     pub title: String,
+    pub poster_url: Option<String>,
+    pub genres: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
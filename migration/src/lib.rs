@@ -2,6 +2,19 @@ pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
 mod m20240930_105435_newsletter_created_at;
+mod m20241020_091500_reminder;
+mod m20241022_184000_program_poster_url;
+mod m20241025_201500_subscription;
+mod m20241028_090000_program_genres;
+mod m20241028_091500_preference;
+mod m20241101_173000_watch;
+mod m20241104_100000_newsletter_approval;
+mod m20241108_093000_newsletter_message;
+mod m20241111_180000_template;
+mod m20241115_090000_newsletter_pin;
+mod m20241118_170000_tonight_message;
+mod m20241121_100000_newsletter_message_target;
+mod m20241125_090000_unaccent;
 
 pub struct Migrator;
 
@@ -11,6 +24,19 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20220101_000001_create_table::Migration),
             Box::new(m20240930_105435_newsletter_created_at::Migration),
+            Box::new(m20241020_091500_reminder::Migration),
+            Box::new(m20241022_184000_program_poster_url::Migration),
+            Box::new(m20241025_201500_subscription::Migration),
+            Box::new(m20241028_090000_program_genres::Migration),
+            Box::new(m20241028_091500_preference::Migration),
+            Box::new(m20241101_173000_watch::Migration),
+            Box::new(m20241104_100000_newsletter_approval::Migration),
+            Box::new(m20241108_093000_newsletter_message::Migration),
+            Box::new(m20241111_180000_template::Migration),
+            Box::new(m20241115_090000_newsletter_pin::Migration),
+            Box::new(m20241118_170000_tonight_message::Migration),
+            Box::new(m20241121_100000_newsletter_message_target::Migration),
+            Box::new(m20241125_090000_unaccent::Migration),
         ]
     }
 }
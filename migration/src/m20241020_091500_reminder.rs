@@ -0,0 +1,58 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Reminder::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Reminder::Id))
+                    .col(big_integer(Reminder::ChatId))
+                    .col(integer(Reminder::EntryId))
+                    .col(boolean(Reminder::Sent).default(false))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_reminder_entry")
+                            .from(Reminder::Table, Reminder::EntryId)
+                            .to(Entry::Table, Entry::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .index(
+                        Index::create()
+                            .name("idx_reminder_chat_entry")
+                            .col(Reminder::ChatId)
+                            .col(Reminder::EntryId)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Reminder::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Reminder {
+    Table,
+    Id,
+    ChatId,
+    EntryId,
+    Sent,
+}
+
+#[derive(DeriveIden)]
+enum Entry {
+    Table,
+    Id,
+}
@@ -0,0 +1,41 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Preference::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Preference::Id))
+                    .col(big_integer_uniq(Preference::ChatId))
+                    .col(boolean(Preference::OriginalVersionOnly).default(false))
+                    .col(integer(Preference::TimeSlots))
+                    .col(integer(Preference::Weekdays))
+                    .col(string(Preference::ExcludedGenres).default(""))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Preference::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Preference {
+    Table,
+    Id,
+    ChatId,
+    OriginalVersionOnly,
+    TimeSlots,
+    Weekdays,
+    ExcludedGenres,
+}
@@ -0,0 +1,42 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Watch::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Watch::Id))
+                    .col(big_integer(Watch::ChatId))
+                    .col(string(Watch::Title))
+                    .index(
+                        Index::create()
+                            .name("idx_watch_chat_title")
+                            .col(Watch::ChatId)
+                            .col(Watch::Title)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Watch::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Watch {
+    Table,
+    Id,
+    ChatId,
+    Title,
+}
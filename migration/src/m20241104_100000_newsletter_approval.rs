@@ -0,0 +1,46 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Newsletter::Table)
+                    .add_column(
+                        ColumnDef::new(Newsletter::PreviewMessageId)
+                            .integer()
+                            .null(),
+                    )
+                    .add_column(
+                        ColumnDef::new(Newsletter::PublishAt)
+                            .timestamp_with_time_zone()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Newsletter::Table)
+                    .drop_column(Newsletter::PreviewMessageId)
+                    .drop_column(Newsletter::PublishAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Newsletter {
+    Table,
+    PreviewMessageId,
+    PublishAt,
+}
@@ -0,0 +1,83 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(NewsletterMessage::Table)
+                    .if_not_exists()
+                    .col(pk_auto(NewsletterMessage::Id))
+                    .col(integer(NewsletterMessage::NewsletterId))
+                    .col(integer(NewsletterMessage::Position))
+                    .col(integer(NewsletterMessage::MessageId))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_newsletter_message_newsletter")
+                            .from(NewsletterMessage::Table, NewsletterMessage::NewsletterId)
+                            .to(Newsletter::Table, Newsletter::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .index(
+                        Index::create()
+                            .name("idx_newsletter_message_position")
+                            .col(NewsletterMessage::NewsletterId)
+                            .col(NewsletterMessage::Position)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // newsletters published so far were sent as a single message
+        manager
+            .exec_stmt(
+                Query::insert()
+                    .into_table(NewsletterMessage::Table)
+                    .columns([
+                        NewsletterMessage::NewsletterId,
+                        NewsletterMessage::Position,
+                        NewsletterMessage::MessageId,
+                    ])
+                    .select_from(
+                        Query::select()
+                            .column(Newsletter::Id)
+                            .expr(Expr::val(0))
+                            .column(Newsletter::MessageId)
+                            .from(Newsletter::Table)
+                            .and_where(Expr::col(Newsletter::MessageId).is_not_null())
+                            .to_owned(),
+                    )
+                    .map_err(|e| DbErr::Custom(e.to_string()))?
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(NewsletterMessage::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum NewsletterMessage {
+    Table,
+    Id,
+    NewsletterId,
+    Position,
+    MessageId,
+}
+
+#[derive(DeriveIden)]
+enum Newsletter {
+    Table,
+    Id,
+    MessageId,
+}
@@ -0,0 +1,35 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Template::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Template::Id))
+                    .col(string_uniq(Template::Name))
+                    .col(text(Template::Source))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Template::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Template {
+    Table,
+    Id,
+    Name,
+    Source,
+}
@@ -0,0 +1,35 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(TonightMessage::Table)
+                    .if_not_exists()
+                    .col(pk_auto(TonightMessage::Id))
+                    .col(integer(TonightMessage::MessageId))
+                    .col(date(TonightMessage::PostedOn))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(TonightMessage::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum TonightMessage {
+    Table,
+    Id,
+    MessageId,
+    PostedOn,
+}
@@ -0,0 +1,89 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // messages sent so far were all published to `CHANNEL_ID`
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(NewsletterMessage::Table)
+                    .add_column(string(NewsletterMessage::Target).default("channel"))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_newsletter_message_position")
+                    .table(NewsletterMessage::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_newsletter_message_target_position")
+                    .table(NewsletterMessage::Table)
+                    .col(NewsletterMessage::NewsletterId)
+                    .col(NewsletterMessage::Target)
+                    .col(NewsletterMessage::Position)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_newsletter_message_target_position")
+                    .table(NewsletterMessage::Table)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .exec_stmt(
+                Query::delete()
+                    .from_table(NewsletterMessage::Table)
+                    .and_where(Expr::col(NewsletterMessage::Target).ne("channel"))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(NewsletterMessage::Table)
+                    .drop_column(NewsletterMessage::Target)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_newsletter_message_position")
+                    .table(NewsletterMessage::Table)
+                    .col(NewsletterMessage::NewsletterId)
+                    .col(NewsletterMessage::Position)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum NewsletterMessage {
+    Table,
+    NewsletterId,
+    Target,
+    Position,
+}
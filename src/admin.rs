@@ -0,0 +1,291 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use chrono_tz::Europe;
+use sea_orm::{ActiveModelTrait, ActiveValue, EntityTrait, ModelTrait, QueryOrder};
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::ParseMode;
+use teloxide::utils::command::BotCommands;
+use tracing::{error, info};
+
+use crate::corrections::{self, CorrectionDialogue, CorrectionStorage};
+use crate::templates::Templates;
+use crate::{
+    fetch_newsletter_entry, make_messages, messages, update_latest_newsletter, ServerState,
+    BOT_SCHEDULE_LABEL,
+};
+
+/// Commands available in the error chat, to manage the bot without calling `/update` or
+/// touching the database
+#[derive(BotCommands, Clone, Debug)]
+#[command(rename_rule = "lowercase", description = "Comandi di amministrazione:")]
+pub enum AdminCommand {
+    #[command(description = "ultima newsletter e prossimo aggiornamento")]
+    Stato,
+    #[command(
+        description = "aggiorna il messaggio dell'ultima newsletter e il prossimo aggiornamento"
+    )]
+    Aggiorna,
+    #[command(description = "pubblica di nuovo la newsletter con l'id indicato")]
+    Ripubblica(String),
+    #[command(description = "elimina la newsletter con l'id indicato e il suo messaggio")]
+    Elimina(String),
+    #[command(description = "mostra il messaggio dell'ultima newsletter senza pubblicarlo")]
+    Anteprima,
+    #[command(description = "corregge titoli e proiezioni di una newsletter")]
+    Correggi,
+    #[command(description = "ricarica i modelli dei messaggi da file e database")]
+    Ricarica,
+}
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    Update::filter_message()
+        .filter(|msg: Message, state: Arc<ServerState>| msg.chat.id == state.error_chat_id)
+        .filter_command::<AdminCommand>()
+        .endpoint(handle_admin_command)
+}
+
+async fn handle_admin_command(
+    bot: Bot,
+    msg: Message,
+    command: AdminCommand,
+    state: Arc<ServerState>,
+    storage: Arc<CorrectionStorage>,
+) -> anyhow::Result<()> {
+    let result = match command {
+        AdminCommand::Stato => status_message(&state).await.map(Some),
+        AdminCommand::Aggiorna => update_latest_newsletter(state.clone())
+            .await
+            .map(|_| Some("Messaggio dell'ultima newsletter aggiornato".to_string())),
+        AdminCommand::Ripubblica(id) => match parse_newsletter_id(&id) {
+            Ok(id) => republish_newsletter(&state, id).await.map(|message_id| {
+                Some(format!(
+                    "Newsletter ripubblicata nel messaggio {}",
+                    message_id
+                ))
+            }),
+            Err(e) => Err(e),
+        },
+        AdminCommand::Elimina(id) => match parse_newsletter_id(&id) {
+            Ok(id) => delete_newsletter(&state, id)
+                .await
+                .map(|_| Some(format!("Newsletter {} eliminata", id))),
+            Err(e) => Err(e),
+        },
+        AdminCommand::Anteprima => send_preview(&bot, &state, msg.chat.id).await.map(|_| None),
+        AdminCommand::Ricarica => reload_templates(&state).await.map(|_| {
+            Some(
+                "Modelli ricaricati, usa /aggiorna per applicarli all'ultima newsletter"
+                    .to_string(),
+            )
+        }),
+        AdminCommand::Correggi => {
+            let dialogue = CorrectionDialogue::new(storage, msg.chat.id);
+            corrections::start(&bot, &dialogue, &state)
+                .await
+                .map(|_| None)
+        }
+    };
+
+    let reply = match result {
+        Ok(Some(reply)) => reply,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            error!("{:#}", e);
+            format!("Errore: {:#}", e)
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .await
+        .context("Unable to reply to admin command")?;
+
+    Ok(())
+}
+
+fn parse_newsletter_id(id: &str) -> anyhow::Result<i32> {
+    id.trim()
+        .parse()
+        .map_err(|_| anyhow!("Indica l'id numerico della newsletter, come mostrato da /stato"))
+}
+
+async fn status_message(state: &ServerState) -> anyhow::Result<String> {
+    let latest_newsletter = entity::newsletter::Entity::find()
+        .order_by_desc(entity::newsletter::Column::CreatedAt)
+        .one(&state.db_connection)
+        .await
+        .context("Could not fetch latest newsletter from db")?;
+
+    let newsletter_status = match latest_newsletter {
+        Some(newsletter) => format!(
+            "Ultima newsletter: {} del {}\n{}\nMessaggio: {}",
+            newsletter.id,
+            newsletter
+                .created_at
+                .with_timezone(&Europe::Rome)
+                .format("%d/%m/%Y %H:%M"),
+            newsletter.link,
+            newsletter
+                .message_id
+                .map(|id| id.to_string())
+                .unwrap_or("non pubblicato".to_string()),
+        ),
+        None => "Nessuna newsletter salvata".to_string(),
+    };
+
+    let next_update = state
+        .crontap_client
+        .list_schedules(
+            None,
+            None,
+            None,
+            Some(&state.crontap_api_key),
+            Some(&state.crontap_client_id),
+        )
+        .await
+        .context("Unable to list schedules from crontap")?
+        .into_inner()
+        .schedules
+        .into_iter()
+        .rfind(|s| s.label == BOT_SCHEDULE_LABEL)
+        .map(|s| format_schedule_interval(&s.interval))
+        .unwrap_or("nessuno".to_string());
+
+    Ok(format!(
+        "{}\nProssimo aggiornamento: {}",
+        newsletter_status, next_update
+    ))
+}
+
+/// Formats the `minute hour day month *` intervals set by [`crate::update_schedules`] as a date,
+/// other intervals are shown as they are
+fn format_schedule_interval(interval: &str) -> String {
+    let fields = interval
+        .split_whitespace()
+        .take(4)
+        .map(|f| f.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>();
+
+    match fields.as_deref() {
+        Some([minute, hour, day, month]) => {
+            format!("{:02}/{:02} {:02}:{:02}", day, month, hour, minute)
+        }
+        _ => interval.to_string(),
+    }
+}
+
+/// Posts the published newsletter with the given id to every target again, deleting its previous
+/// messages.
+///
+/// Newsletters waiting for approval are rejected, as they can only be published from their preview.
+async fn republish_newsletter(state: &ServerState, newsletter_id: i32) -> anyhow::Result<i32> {
+    let _lock = state.publish_lock.lock().await;
+
+    let newsletter = entity::newsletter::Entity::find_by_id(newsletter_id)
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch newsletter")?
+        .ok_or(anyhow!("Nessuna newsletter con id {}", newsletter_id))?;
+    if newsletter.message_id.is_none() {
+        bail!(
+            "La newsletter {} non è ancora stata pubblicata, approvala dall'anteprima",
+            newsletter_id
+        );
+    }
+    let newsletter_entry = fetch_newsletter_entry(&state.db_connection, &newsletter).await?;
+
+    let mut first_message_ids = Vec::new();
+    for target in &state.targets {
+        let previous_message_ids =
+            messages::newsletter_message_ids(&state.db_connection, target, newsletter_id).await?;
+        let message_ids = messages::send_newsletter_messages(
+            state,
+            target,
+            newsletter_id,
+            &make_messages(state, target, &newsletter_entry)?,
+        )
+        .await?;
+
+        for previous_message_id in previous_message_ids {
+            messages::delete_message(state, target.chat_id, previous_message_id).await;
+        }
+        first_message_ids.push(message_ids[0]);
+    }
+
+    let mut newsletter: entity::newsletter::ActiveModel = newsletter.into();
+    newsletter.message_id = ActiveValue::Set(Some(first_message_ids[0].0));
+    newsletter
+        .update(&state.db_connection)
+        .await
+        .context("Unable to update newsletter with message id")?;
+    messages::pin_latest_newsletter(state).await?;
+
+    info!("Republished newsletter {}", newsletter_id);
+    Ok(first_message_ids[0].0)
+}
+
+/// Deletes the newsletter with the given id, along with its programs, screenings and messages
+async fn delete_newsletter(state: &ServerState, newsletter_id: i32) -> anyhow::Result<()> {
+    let newsletter = entity::newsletter::Entity::find_by_id(newsletter_id)
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch newsletter")?
+        .ok_or(anyhow!("Nessuna newsletter con id {}", newsletter_id))?;
+
+    for target in &state.targets {
+        let message_ids =
+            messages::newsletter_message_ids(&state.db_connection, target, newsletter_id).await?;
+        for message_id in message_ids {
+            messages::delete_message(state, target.chat_id, message_id).await;
+        }
+    }
+
+    newsletter
+        .delete(&state.db_connection)
+        .await
+        .context("Unable to delete newsletter")?;
+    messages::pin_latest_newsletter(state).await?;
+
+    info!("Deleted newsletter {}", newsletter_id);
+    Ok(())
+}
+
+async fn reload_templates(state: &ServerState) -> anyhow::Result<()> {
+    let templates =
+        Templates::load(state.templates_directory.as_deref(), &state.db_connection).await?;
+    *state.templates.write().unwrap() = templates;
+
+    info!("Reloaded templates");
+    Ok(())
+}
+
+async fn send_preview(bot: &Bot, state: &ServerState, chat_id: ChatId) -> anyhow::Result<()> {
+    let latest_newsletter = entity::newsletter::Entity::find()
+        .order_by_desc(entity::newsletter::Column::CreatedAt)
+        .one(&state.db_connection)
+        .await
+        .context("Could not fetch latest newsletter from db")?
+        .ok_or(anyhow!("Nessuna newsletter salvata"))?;
+    let newsletter_entry = fetch_newsletter_entry(&state.db_connection, &latest_newsletter).await?;
+
+    for text in make_messages(state, state.main_target(), &newsletter_entry)? {
+        bot.send_message(chat_id, text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .await
+            .context("Unable to send preview")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admin::format_schedule_interval;
+
+    #[test]
+    fn schedule_intervals_are_formatted_as_dates() {
+        assert_eq!(format_schedule_interval("15 21 1 10 *"), "01/10 21:15");
+        assert_eq!(format_schedule_interval("*/5 * * * *"), "*/5 * * * *");
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Europe;
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, ModelTrait, QueryFilter};
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, MessageId, ParseMode};
+use teloxide::utils::markdown;
+use teloxide::{ApiError, RequestError};
+use tracing::{error, info};
+
+use crate::corrections::{self, CorrectionDialogue, CorrectionStorage};
+use crate::inbound::Attachment;
+use crate::messages::{self, MESSAGE_LENGTH_LIMIT};
+use crate::parser::NewsletterEntry;
+use crate::{fetch_newsletter_entry, make_messages_within, publish_newsletter, ServerState};
+
+const CALLBACK_PREFIX: &str = "approval:";
+
+/// How often newsletters awaiting approval are checked for auto-publishing
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Room left in previews for the approval status, the rest showing the first newsletter message
+const PREVIEW_RESERVED_LENGTH: usize = 300;
+
+/// Attachments forwarded to the channel along with a newsletter
+#[derive(Default)]
+pub struct NewsletterAttachments {
+    pub inline_images: Vec<Attachment>,
+    pub documents: Vec<Attachment>,
+}
+
+/// Attachments of the newsletters awaiting approval, by newsletter id.
+///
+/// They are only kept in memory, so newsletters approved after a restart are published
+/// without attachments.
+pub type PendingAttachments = Mutex<HashMap<i32, NewsletterAttachments>>;
+
+/// Serializes changes to the newsletters awaiting approval, so that e.g. a newsletter approved
+/// while being auto-published isn't posted twice
+pub type PublishLock = tokio::sync::Mutex<()>;
+
+/// Settings of the approval of newsletters before publishing, enabled by `APPROVAL_REQUIRED` or
+/// `APPROVAL_TIMEOUT_MINUTES`
+pub struct ApprovalSettings {
+    /// If set, newsletters are published automatically once this time has passed without
+    /// being approved
+    pub timeout: Option<chrono::Duration>,
+}
+
+fn is_auto_publish_enabled(state: &ServerState) -> bool {
+    state
+        .approval
+        .as_ref()
+        .is_some_and(|approval| approval.timeout.is_some())
+}
+
+/// Actions of the approval inline keyboard, encoded as `approval:<action>:<newsletter id>`
+/// callback data
+enum ApprovalAction {
+    Publish(i32),
+    /// Stops auto-publishing, so that the newsletter can be corrected before approving it
+    Edit(i32),
+    /// Renders the preview again, after the newsletter has been corrected
+    Refresh(i32),
+    Discard(i32),
+}
+
+impl ApprovalAction {
+    fn to_callback_data(&self) -> String {
+        match self {
+            ApprovalAction::Publish(id) => format!("{}publish:{}", CALLBACK_PREFIX, id),
+            ApprovalAction::Edit(id) => format!("{}edit:{}", CALLBACK_PREFIX, id),
+            ApprovalAction::Refresh(id) => format!("{}refresh:{}", CALLBACK_PREFIX, id),
+            ApprovalAction::Discard(id) => format!("{}discard:{}", CALLBACK_PREFIX, id),
+        }
+    }
+
+    fn from_callback_data(data: &str) -> anyhow::Result<Self> {
+        let (action, id) = data
+            .strip_prefix(CALLBACK_PREFIX)
+            .and_then(|d| d.split_once(':'))
+            .ok_or(anyhow!("Invalid approval callback data: '{}'", data))?;
+        let id =
+            i32::from_str(id).with_context(|| format!("Invalid id in callback data '{}'", data))?;
+
+        match action {
+            "publish" => Ok(ApprovalAction::Publish(id)),
+            "edit" => Ok(ApprovalAction::Edit(id)),
+            "refresh" => Ok(ApprovalAction::Refresh(id)),
+            "discard" => Ok(ApprovalAction::Discard(id)),
+            _ => Err(anyhow!("Unknown approval action: '{}'", action)),
+        }
+    }
+}
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    Update::filter_callback_query()
+        .filter(|q: CallbackQuery, state: Arc<ServerState>| {
+            q.data
+                .as_deref()
+                .is_some_and(|d| d.starts_with(CALLBACK_PREFIX))
+                && q.regular_message()
+                    .is_some_and(|m| m.chat.id == state.error_chat_id)
+        })
+        .endpoint(handle_callback)
+}
+
+/// Sends the preview of a saved newsletter to the error chat, to be approved before
+/// `approval_timeout` passes, if any
+pub async fn request_approval(
+    state: &ServerState,
+    mut saved_newsletter: entity::newsletter::ActiveModel,
+    newsletter_entry: &NewsletterEntry,
+    attachments: NewsletterAttachments,
+    approval_timeout: Option<chrono::Duration>,
+) -> anyhow::Result<()> {
+    let newsletter_id = *saved_newsletter
+        .id
+        .try_as_ref()
+        .ok_or(anyhow!("Saved newsletter has no id"))?;
+    let publish_at = approval_timeout.map(|timeout| (Utc::now() + timeout).fixed_offset());
+
+    let message = state
+        .bot
+        .send_message(
+            state.error_chat_id,
+            preview_text(state, newsletter_entry, publish_at)?,
+        )
+        .parse_mode(ParseMode::MarkdownV2)
+        .reply_markup(preview_keyboard(state, newsletter_id, publish_at))
+        .await
+        .context("Unable to send newsletter preview")?;
+
+    saved_newsletter.preview_message_id = ActiveValue::Set(Some(message.id.0));
+    saved_newsletter.publish_at = ActiveValue::Set(publish_at);
+    saved_newsletter
+        .save(&state.db_connection)
+        .await
+        .context("Unable to update newsletter with preview message id")?;
+
+    state
+        .pending_attachments
+        .lock()
+        .unwrap()
+        .insert(newsletter_id, attachments);
+
+    info!("Requested approval for newsletter {}", newsletter_id);
+    Ok(())
+}
+
+fn preview_text(
+    state: &ServerState,
+    newsletter_entry: &NewsletterEntry,
+    publish_at: Option<DateTime<FixedOffset>>,
+) -> anyhow::Result<String> {
+    let status = match publish_at {
+        Some(publish_at) => format!(
+            "Anteprima: la newsletter sarà pubblicata automaticamente alle {}",
+            publish_at
+                .with_timezone(&Europe::Rome)
+                .format("%H:%M del %d/%m")
+        ),
+        None if is_auto_publish_enabled(state) => {
+            "Anteprima: pubblicazione automatica sospesa, correggi la newsletter e \
+            aggiorna l'anteprima prima di pubblicarla"
+                .to_string()
+        }
+        None => "Anteprima: la newsletter sarà pubblicata solo dopo l'approvazione".to_string(),
+    };
+
+    let mut texts = make_messages_within(
+        state,
+        state.main_target(),
+        newsletter_entry,
+        MESSAGE_LENGTH_LIMIT - PREVIEW_RESERVED_LENGTH,
+    )?;
+    let other_messages = match texts.len() {
+        1 => String::new(),
+        n => format!(
+            "\n\n_{}_",
+            markdown::escape(&format!(
+                "La newsletter sarà pubblicata in {} messaggi, usa /anteprima per vederli tutti",
+                n
+            ))
+        ),
+    };
+
+    Ok(format!(
+        "_{}_\n\n{}{}",
+        markdown::escape(&status),
+        texts.swap_remove(0),
+        other_messages
+    ))
+}
+
+fn preview_keyboard(
+    state: &ServerState,
+    newsletter_id: i32,
+    publish_at: Option<DateTime<FixedOffset>>,
+) -> InlineKeyboardMarkup {
+    let button = |text: &str, action: ApprovalAction| {
+        InlineKeyboardButton::callback(text, action.to_callback_data())
+    };
+
+    // once auto-publishing is suspended the newsletter is already being corrected
+    let middle_button = if publish_at.is_some() || !is_auto_publish_enabled(state) {
+        button("✏️ Modifica", ApprovalAction::Edit(newsletter_id))
+    } else {
+        button(
+            "🔄 Aggiorna anteprima",
+            ApprovalAction::Refresh(newsletter_id),
+        )
+    };
+
+    InlineKeyboardMarkup::new(vec![vec![
+        button("✅ Pubblica", ApprovalAction::Publish(newsletter_id)),
+        middle_button,
+        button("❌ Scarta", ApprovalAction::Discard(newsletter_id)),
+    ]])
+}
+
+async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    state: Arc<ServerState>,
+    storage: Arc<CorrectionStorage>,
+) -> anyhow::Result<()> {
+    let action = ApprovalAction::from_callback_data(q.data.as_deref().unwrap_or_default())?;
+
+    let answer = match action {
+        ApprovalAction::Publish(newsletter_id) => {
+            let _lock = state.publish_lock.lock().await;
+            match publish_pending_newsletter(&state, newsletter_id).await? {
+                true => "Newsletter pubblicata",
+                false => "La newsletter non è più in attesa di approvazione",
+            }
+        }
+        ApprovalAction::Edit(newsletter_id) => {
+            let _lock = state.publish_lock.lock().await;
+            match find_pending_newsletter(&state, newsletter_id).await? {
+                Some(newsletter) => {
+                    let mut newsletter: entity::newsletter::ActiveModel = newsletter.into();
+                    newsletter.publish_at = ActiveValue::Set(None);
+                    let newsletter = newsletter
+                        .update(&state.db_connection)
+                        .await
+                        .context("Unable to suspend auto-publishing")?;
+                    update_preview(&state, &newsletter).await?;
+
+                    let dialogue = CorrectionDialogue::new(storage, state.error_chat_id);
+                    corrections::start_for_newsletter(&bot, &dialogue, &state, newsletter.id)
+                        .await?;
+
+                    if is_auto_publish_enabled(&state) {
+                        "Pubblicazione automatica sospesa"
+                    } else {
+                        "Correggi la newsletter prima di pubblicarla"
+                    }
+                }
+                None => "La newsletter non è più in attesa di approvazione",
+            }
+        }
+        ApprovalAction::Refresh(newsletter_id) => {
+            match find_pending_newsletter(&state, newsletter_id).await? {
+                Some(newsletter) => {
+                    update_preview(&state, &newsletter).await?;
+
+                    "Anteprima aggiornata"
+                }
+                None => "La newsletter non è più in attesa di approvazione",
+            }
+        }
+        ApprovalAction::Discard(newsletter_id) => {
+            let _lock = state.publish_lock.lock().await;
+            match find_pending_newsletter(&state, newsletter_id).await? {
+                Some(newsletter) => {
+                    let preview_message_id = newsletter.preview_message_id;
+                    newsletter
+                        .delete(&state.db_connection)
+                        .await
+                        .context("Unable to delete discarded newsletter")?;
+                    state
+                        .pending_attachments
+                        .lock()
+                        .unwrap()
+                        .remove(&newsletter_id);
+
+                    if let Some(preview_message_id) = preview_message_id {
+                        close_preview(&state, preview_message_id, "❌ Newsletter scartata").await?;
+                    }
+                    info!("Discarded newsletter {}", newsletter_id);
+
+                    "Newsletter scartata"
+                }
+                None => "La newsletter non è più in attesa di approvazione",
+            }
+        }
+    };
+
+    bot.answer_callback_query(q.id).text(answer).await?;
+
+    Ok(())
+}
+
+/// Finds a newsletter that is waiting for approval
+async fn find_pending_newsletter(
+    state: &ServerState,
+    newsletter_id: i32,
+) -> anyhow::Result<Option<entity::newsletter::Model>> {
+    entity::newsletter::Entity::find_by_id(newsletter_id)
+        .filter(entity::newsletter::Column::MessageId.is_null())
+        .filter(entity::newsletter::Column::PreviewMessageId.is_not_null())
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch newsletter")
+}
+
+/// Publishes a newsletter waiting for approval, returning `false` if it was already published
+/// or discarded. Must be called while holding the [`PublishLock`] of `state`.
+async fn publish_pending_newsletter(
+    state: &ServerState,
+    newsletter_id: i32,
+) -> anyhow::Result<bool> {
+    let Some(newsletter) = find_pending_newsletter(state, newsletter_id).await? else {
+        return Ok(false);
+    };
+
+    let newsletter_entry = fetch_newsletter_entry(&state.db_connection, &newsletter).await?;
+    let attachments = state
+        .pending_attachments
+        .lock()
+        .unwrap()
+        .remove(&newsletter_id)
+        .unwrap_or_default();
+    let preview_message_id = newsletter.preview_message_id;
+
+    publish_newsletter(state, newsletter.into(), &newsletter_entry, &attachments).await?;
+    info!("Published newsletter {} after approval", newsletter_id);
+
+    if let Some(preview_message_id) = preview_message_id {
+        close_preview(state, preview_message_id, "✅ Newsletter pubblicata").await?;
+    }
+
+    Ok(true)
+}
+
+pub async fn update_preview(
+    state: &ServerState,
+    newsletter: &entity::newsletter::Model,
+) -> anyhow::Result<()> {
+    let preview_message_id = newsletter
+        .preview_message_id
+        .ok_or(anyhow!("Newsletter {} has no preview", newsletter.id))?;
+    let newsletter_entry = fetch_newsletter_entry(&state.db_connection, newsletter).await?;
+
+    let text = preview_text(state, &newsletter_entry, newsletter.publish_at)?;
+    let result = messages::with_retries(|| {
+        state
+            .bot
+            .edit_message_text(state.error_chat_id, MessageId(preview_message_id), &text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_markup(preview_keyboard(
+                state,
+                newsletter.id,
+                newsletter.publish_at,
+            ))
+            .send()
+    })
+    .await;
+
+    // refreshing a preview that didn't change isn't an error
+    match result {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+        Err(e) => Err(e).context("Unable to update newsletter preview"),
+    }
+}
+
+/// Replaces a preview with the outcome of the approval, removing its buttons
+async fn close_preview(
+    state: &ServerState,
+    preview_message_id: i32,
+    outcome: &str,
+) -> anyhow::Result<()> {
+    state
+        .bot
+        .edit_message_text(state.error_chat_id, MessageId(preview_message_id), outcome)
+        .await
+        .context("Unable to update newsletter preview")?;
+
+    Ok(())
+}
+
+/// Periodically publishes the newsletters whose approval timeout has passed
+pub async fn run_scheduler(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = publish_expired_newsletters(&state).await {
+            error!("Unable to auto-publish newsletters: {:#}", e);
+        }
+    }
+}
+
+async fn publish_expired_newsletters(state: &ServerState) -> anyhow::Result<()> {
+    let _lock = state.publish_lock.lock().await;
+    let expired_newsletters = entity::newsletter::Entity::find()
+        .filter(entity::newsletter::Column::MessageId.is_null())
+        .filter(entity::newsletter::Column::PreviewMessageId.is_not_null())
+        .filter(entity::newsletter::Column::PublishAt.lte(Utc::now()))
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch newsletters awaiting approval")?;
+
+    // a newsletter failing to publish doesn't hold back the other ones
+    for newsletter in expired_newsletters {
+        info!("Approval timeout passed for newsletter {}", newsletter.id);
+        if let Err(e) = publish_pending_newsletter(state, newsletter.id).await {
+            error!(
+                "Unable to auto-publish newsletter {}: {:#}",
+                newsletter.id, e
+            );
+        }
+    }
+
+    Ok(())
+}
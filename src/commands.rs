@@ -0,0 +1,432 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::{DateTime, Days, NaiveDate, TimeZone, Utc};
+use chrono_tz::{Europe, Tz};
+use itertools::Itertools;
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::{BotCommand, InlineKeyboardMarkup, ParseMode};
+use teloxide::utils::command::BotCommands;
+use teloxide::utils::markdown;
+use teloxide::RequestError;
+
+use crate::parser::ProgrammingEntry;
+use crate::subscriptions::{self, DigestContent};
+use crate::{
+    admin, approval, corrections, fetch_programming_entries_between, format_programming_entry,
+    messages, preferences, reminders, search, watchlist, ServerState,
+};
+
+/// Commands available to subscribers in private chats, some of which are also available in groups
+#[derive(BotCommands, Clone, Debug)]
+#[command(rename_rule = "lowercase", description = "Comandi disponibili:")]
+pub enum Command {
+    #[command(description = "mostra questo messaggio")]
+    Start,
+    #[command(description = "mostra questo messaggio")]
+    Help,
+    #[command(description = "proiezioni di oggi")]
+    Oggi,
+    #[command(description = "proiezioni di domani")]
+    Domani,
+    #[command(description = "proiezioni dei prossimi 7 giorni")]
+    Settimana,
+    #[command(description = "il prossimo film in programma")]
+    Prossimo,
+    #[command(description = "i tuoi promemoria")]
+    Promemoria,
+    #[command(description = "cerca un film per titolo")]
+    Cerca(String),
+    #[command(
+        description = "ricevi un messaggio quando un film sarà in programma, ad esempio /segui anora"
+    )]
+    Segui(String),
+    #[command(description = "scegli quali proiezioni ricevere in promemoria e riepiloghi")]
+    Preferenze,
+    #[command(
+        description = "ricevi ogni giorno le proiezioni di oggi, domani o della settimana, \
+        ad esempio /abbonati 08:30 domani"
+    )]
+    Abbonati(String),
+    #[command(description = "smetti di ricevere le proiezioni ogni giorno")]
+    Disiscriviti,
+}
+
+impl Command {
+    fn is_available_in_groups(&self) -> bool {
+        matches!(
+            self,
+            Command::Help | Command::Abbonati(_) | Command::Disiscriviti
+        )
+    }
+}
+
+/// Commands to show in the menu of group chats
+pub fn group_bot_commands() -> Vec<BotCommand> {
+    let group_commands = ["help", "abbonati", "disiscriviti"];
+
+    Command::bot_commands()
+        .into_iter()
+        .filter(|c| group_commands.contains(&c.command.trim_start_matches('/')))
+        .collect()
+}
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    dptree::entry()
+        .branch(admin::schema())
+        .branch(approval::schema())
+        .branch(corrections::schema())
+        .branch(
+            Update::filter_message()
+                .filter_command::<Command>()
+                .filter(|msg: Message, command: Command| {
+                    msg.chat.is_private() || command.is_available_in_groups()
+                })
+                .endpoint(handle_command),
+        )
+        .branch(reminders::schema())
+        .branch(preferences::schema())
+        .branch(watchlist::schema())
+        .branch(search::schema())
+}
+
+async fn handle_command(
+    bot: Bot,
+    msg: Message,
+    command: Command,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let today = Utc::now().with_timezone(&Europe::Rome).date_naive();
+    let now = Utc::now().with_timezone(&Europe::Rome);
+
+    if matches!(command, Command::Abbonati(_) | Command::Disiscriviti)
+        && !can_manage_chat(&bot, &msg).await?
+    {
+        bot.send_message(
+            msg.chat.id,
+            "Solo gli amministratori del gruppo possono gestire l'abbonamento.",
+        )
+        .await
+        .context("Unable to reply to command")?;
+
+        return Ok(());
+    }
+
+    let (heading, from, to) = match command {
+        Command::Start | Command::Help => {
+            bot.send_message(msg.chat.id, Command::descriptions().to_string())
+                .await
+                .context("Unable to reply to command")?;
+
+            return Ok(());
+        }
+        Command::Promemoria => {
+            let (text, keyboard) = reminders::reminders_message(&state, msg.chat.id).await?;
+            bot.send_message(msg.chat.id, text)
+                .parse_mode(ParseMode::MarkdownV2)
+                .reply_markup(keyboard)
+                .await
+                .context("Unable to reply to command")?;
+
+            return Ok(());
+        }
+        Command::Segui(title) => {
+            follow_command(&bot, &msg, &state, title.trim()).await?;
+
+            return Ok(());
+        }
+        Command::Preferenze => {
+            let (text, keyboard) = preferences::preferences_message(&state, msg.chat.id).await?;
+            bot.send_message(msg.chat.id, text)
+                .reply_markup(keyboard)
+                .await
+                .context("Unable to reply to command")?;
+
+            return Ok(());
+        }
+        Command::Cerca(query) => {
+            search_films_command(&bot, &msg, &state, &query).await?;
+
+            return Ok(());
+        }
+        Command::Abbonati(args) => {
+            subscribe_command(&bot, &msg, &state, &args).await?;
+
+            return Ok(());
+        }
+        Command::Disiscriviti => {
+            let text = if subscriptions::unsubscribe(&state, msg.chat.id).await? {
+                "Non riceverai più le proiezioni ogni giorno."
+            } else {
+                "Questa chat non è abbonata, usa /abbonati per ricevere le proiezioni ogni giorno."
+            };
+            bot.send_message(msg.chat.id, text)
+                .await
+                .context("Unable to reply to command")?;
+
+            return Ok(());
+        }
+        Command::Oggi => DigestContent::Today.period(today)?,
+        Command::Domani => DigestContent::Tomorrow.period(today)?,
+        Command::Settimana => DigestContent::Week.period(today)?,
+        Command::Prossimo => (
+            "Prossimo film allo Spazio Alfieri",
+            now,
+            now + Days::new(365),
+        ),
+    };
+
+    let mut entries = fetch_programming_entries_between(&state.db_connection, from, to)
+        .await
+        .context("Unable to fetch screenings")?;
+    if let Command::Prossimo = command {
+        entries.truncate(1);
+    }
+
+    let (texts, keyboard) = screenings_message(&state, heading, &entries).await?;
+    send_screenings(&state, msg.chat.id, &texts, keyboard)
+        .await
+        .context("Unable to reply to command")?;
+
+    Ok(())
+}
+
+/// Whether the sender of `msg` can change the settings of its chat, i.e. the chat is private or
+/// the sender is one of the group administrators
+async fn can_manage_chat(bot: &Bot, msg: &Message) -> anyhow::Result<bool> {
+    if msg.chat.is_private() {
+        return Ok(true);
+    }
+
+    // anonymous administrators send messages on behalf of the group itself
+    if msg
+        .sender_chat
+        .as_ref()
+        .is_some_and(|c| c.id == msg.chat.id)
+    {
+        return Ok(true);
+    }
+
+    let Some(user) = &msg.from else {
+        return Ok(false);
+    };
+    let member = bot
+        .get_chat_member(msg.chat.id, user.id)
+        .await
+        .context("Unable to fetch chat member")?;
+
+    Ok(member.is_privileged())
+}
+
+/// Lists `entries` under `heading`, split into as many messages as needed, with a reminder
+/// button for each film
+pub async fn screenings_message(
+    state: &ServerState,
+    heading: &str,
+    entries: &[ProgrammingEntry],
+) -> anyhow::Result<(Vec<String>, InlineKeyboardMarkup)> {
+    let blocks = if entries.is_empty() {
+        vec![markdown::escape("Nessuna proiezione in programma.")]
+    } else {
+        entries
+            .iter()
+            .map(|e| format_programming_entry(state, e))
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+
+    Ok((
+        messages::split_message(
+            &format!("_{}_", heading),
+            &blocks,
+            "",
+            messages::MESSAGE_LENGTH_LIMIT,
+        ),
+        reminders::film_keyboard(state, entries).await?,
+    ))
+}
+
+/// Sends the messages built by [`screenings_message`], attaching `keyboard` to the last one
+pub async fn send_screenings(
+    state: &ServerState,
+    chat_id: ChatId,
+    texts: &[String],
+    keyboard: InlineKeyboardMarkup,
+) -> Result<(), RequestError> {
+    let Some((last, others)) = texts.split_last() else {
+        return Ok(());
+    };
+
+    for text in others {
+        messages::send_markdown(state, chat_id, text).await?;
+    }
+    messages::with_retries(|| {
+        state
+            .bot
+            .send_message(chat_id, last)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_markup(keyboard.clone())
+            .send()
+    })
+    .await?;
+
+    Ok(())
+}
+
+async fn follow_command(
+    bot: &Bot,
+    msg: &Message,
+    state: &ServerState,
+    title: &str,
+) -> anyhow::Result<()> {
+    if title.is_empty() {
+        let (text, keyboard) = watchlist::watchlist_message(state, msg.chat.id).await?;
+        bot.send_message(msg.chat.id, text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_markup(keyboard)
+            .await
+            .context("Unable to reply to command")?;
+
+        return Ok(());
+    }
+
+    if !watchlist::is_followable(title) {
+        bot.send_message(
+            msg.chat.id,
+            "Il titolo è troppo generico, scrivine una parte più lunga, ad esempio: /segui anora",
+        )
+        .await
+        .context("Unable to reply to command")?;
+
+        return Ok(());
+    }
+
+    let followed = watchlist::follow(state, msg.chat.id, title).await?;
+
+    // the film might already be programmed, in which case its screenings are shown right away
+    let programmed_films = search::search_films(&state.db_connection, title)
+        .await?
+        .into_iter()
+        .filter(|r| !r.programming_entry.date_entries.is_empty())
+        .collect_vec();
+
+    let mut text = markdown::escape(&if followed {
+        format!(
+            "Ti avviserò quando \"{}\" sarà in programma. Usa /segui senza titolo per vedere i film che segui.",
+            title
+        )
+    } else {
+        format!("Segui già \"{}\".", title)
+    });
+    if !programmed_films.is_empty() {
+        text = format!(
+            "{}\n\n{}\n\n{}",
+            text,
+            markdown::escape("Intanto, ecco cosa è già in programma:"),
+            programmed_films
+                .iter()
+                .map(|f| search::format_film_card(state, f))
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .join("\n\n")
+        );
+    }
+
+    let entries = programmed_films
+        .into_iter()
+        .map(|r| r.programming_entry)
+        .collect_vec();
+    bot.send_message(msg.chat.id, text)
+        .parse_mode(ParseMode::MarkdownV2)
+        .reply_markup(reminders::film_keyboard(state, &entries).await?)
+        .await
+        .context("Unable to reply to command")?;
+
+    Ok(())
+}
+
+async fn subscribe_command(
+    bot: &Bot,
+    msg: &Message,
+    state: &ServerState,
+    args: &str,
+) -> anyhow::Result<()> {
+    let Ok((digest_time, content)) = subscriptions::parse_subscription_args(args) else {
+        bot.send_message(
+            msg.chat.id,
+            "Indica l'ora e le proiezioni da ricevere (oggi, domani o settimana), \
+            ad esempio: /abbonati 08:30 domani",
+        )
+        .await
+        .context("Unable to reply to command")?;
+
+        return Ok(());
+    };
+
+    subscriptions::subscribe(state, msg.chat.id, digest_time, content).await?;
+
+    let content_text = match content {
+        DigestContent::Today => "di oggi",
+        DigestContent::Tomorrow => "di domani",
+        DigestContent::Week => "dei prossimi 7 giorni",
+    };
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Ogni giorno alle {} riceverai le proiezioni {}. Usa /disiscriviti per annullare.",
+            digest_time.format("%H:%M"),
+            content_text
+        ),
+    )
+    .await
+    .context("Unable to reply to command")?;
+
+    Ok(())
+}
+
+async fn search_films_command(
+    bot: &Bot,
+    msg: &Message,
+    state: &ServerState,
+    query: &str,
+) -> anyhow::Result<()> {
+    if query.trim().is_empty() {
+        bot.send_message(
+            msg.chat.id,
+            "Scrivi il titolo da cercare, ad esempio: /cerca sindrome amori",
+        )
+        .await
+        .context("Unable to reply to command")?;
+
+        return Ok(());
+    }
+
+    let results = search::search_films(&state.db_connection, query).await?;
+    let text = if results.is_empty() {
+        markdown::escape(&format!("Nessun film trovato per \"{}\".", query))
+    } else {
+        results
+            .iter()
+            .map(|r| search::format_film_card(state, r))
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .join("\n\n")
+    };
+
+    let entries = results
+        .into_iter()
+        .map(|r| r.programming_entry)
+        .collect_vec();
+    bot.send_message(msg.chat.id, text)
+        .parse_mode(ParseMode::MarkdownV2)
+        .reply_markup(reminders::film_keyboard(state, &entries).await?)
+        .await
+        .context("Unable to reply to command")?;
+
+    Ok(())
+}
+
+pub fn start_of_day(date: NaiveDate) -> anyhow::Result<DateTime<Tz>> {
+    Europe::Rome
+        .from_local_datetime(&date.and_time(Default::default()))
+        .earliest()
+        .with_context(|| format!("Invalid start of day for {}", date))
+}
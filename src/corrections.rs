@@ -0,0 +1,608 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeZone};
+use chrono_tz::{Europe, Tz};
+use itertools::Itertools;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, ModelTrait, QueryFilter, QueryOrder,
+    QuerySelect,
+};
+use teloxide::dispatching::dialogue::{self, InMemStorage};
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::{ForceReply, InlineKeyboardButton, InlineKeyboardMarkup};
+use tracing::info;
+
+use crate::{
+    approval, fetch_latest_newsletter, fetch_newsletter_entry, make_messages, messages,
+    update_latest_newsletter, ServerState,
+};
+
+const CALLBACK_PREFIX: &str = "correct:";
+
+/// Number of newsletters offered when starting a correction
+const NEWSLETTER_CHOICES: u64 = 5;
+
+/// Steps of the admin dialogue correcting the programs and screenings of a newsletter
+#[derive(Clone, Debug, Default)]
+pub enum CorrectionState {
+    #[default]
+    Idle,
+    ChooseNewsletter,
+    ChooseProgram {
+        newsletter_id: i32,
+    },
+    ChooseAction {
+        newsletter_id: i32,
+        program_id: i32,
+    },
+    ReceiveTitle {
+        newsletter_id: i32,
+        program_id: i32,
+    },
+    /// Waiting for the date of a new screening, or of the screening with id `entry_id`
+    ReceiveScreening {
+        newsletter_id: i32,
+        program_id: i32,
+        entry_id: Option<i32>,
+    },
+}
+
+pub type CorrectionStorage = InMemStorage<CorrectionState>;
+pub type CorrectionDialogue = Dialogue<CorrectionState, CorrectionStorage>;
+
+/// Actions of the correction inline keyboards, encoded as `correct:<action>[:<id>]` callback data
+enum CorrectionAction {
+    Newsletter(i32),
+    Program(i32),
+    Title,
+    AddScreening,
+    EditScreening(i32),
+    RemoveScreening(i32),
+    /// Goes back to the choice of the program
+    Back,
+    /// Ends the correction, updating the newsletter message
+    Done,
+}
+
+impl CorrectionAction {
+    fn to_callback_data(&self) -> String {
+        match self {
+            CorrectionAction::Newsletter(id) => format!("{}newsletter:{}", CALLBACK_PREFIX, id),
+            CorrectionAction::Program(id) => format!("{}program:{}", CALLBACK_PREFIX, id),
+            CorrectionAction::Title => format!("{}title", CALLBACK_PREFIX),
+            CorrectionAction::AddScreening => format!("{}add", CALLBACK_PREFIX),
+            CorrectionAction::EditScreening(id) => format!("{}edit:{}", CALLBACK_PREFIX, id),
+            CorrectionAction::RemoveScreening(id) => format!("{}remove:{}", CALLBACK_PREFIX, id),
+            CorrectionAction::Back => format!("{}back", CALLBACK_PREFIX),
+            CorrectionAction::Done => format!("{}done", CALLBACK_PREFIX),
+        }
+    }
+
+    fn from_callback_data(data: &str) -> anyhow::Result<Self> {
+        let action = data
+            .strip_prefix(CALLBACK_PREFIX)
+            .ok_or(anyhow!("Invalid correction callback data: '{}'", data))?;
+        let parse_id = |id: &str| {
+            i32::from_str(id).with_context(|| format!("Invalid id in callback data '{}'", data))
+        };
+
+        match action.split_once(':') {
+            None if action == "title" => Ok(CorrectionAction::Title),
+            None if action == "add" => Ok(CorrectionAction::AddScreening),
+            None if action == "back" => Ok(CorrectionAction::Back),
+            None if action == "done" => Ok(CorrectionAction::Done),
+            Some(("newsletter", id)) => Ok(CorrectionAction::Newsletter(parse_id(id)?)),
+            Some(("program", id)) => Ok(CorrectionAction::Program(parse_id(id)?)),
+            Some(("edit", id)) => Ok(CorrectionAction::EditScreening(parse_id(id)?)),
+            Some(("remove", id)) => Ok(CorrectionAction::RemoveScreening(parse_id(id)?)),
+            _ => Err(anyhow!("Unknown correction action: '{}'", action)),
+        }
+    }
+}
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    dialogue::enter::<Update, CorrectionStorage, CorrectionState, _>()
+        .filter(|update: Update, state: Arc<ServerState>| {
+            update.chat().is_some_and(|c| c.id == state.error_chat_id)
+        })
+        .branch(
+            Update::filter_callback_query()
+                .filter(|q: CallbackQuery| {
+                    q.data
+                        .as_deref()
+                        .is_some_and(|d| d.starts_with(CALLBACK_PREFIX))
+                })
+                .endpoint(handle_callback),
+        )
+        .branch(
+            Update::filter_message()
+                .filter(|correction: CorrectionState| {
+                    matches!(
+                        correction,
+                        CorrectionState::ReceiveTitle { .. }
+                            | CorrectionState::ReceiveScreening { .. }
+                    )
+                })
+                .filter_map(|msg: Message| msg.text().map(str::to_string))
+                .endpoint(receive_text),
+        )
+}
+
+/// Starts a correction by asking which of the latest newsletters to correct
+pub async fn start(
+    bot: &Bot,
+    dialogue: &CorrectionDialogue,
+    state: &ServerState,
+) -> anyhow::Result<()> {
+    let newsletters = entity::newsletter::Entity::find()
+        .order_by_desc(entity::newsletter::Column::CreatedAt)
+        .limit(NEWSLETTER_CHOICES)
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch newsletters")?;
+
+    let buttons = newsletters
+        .iter()
+        .map(|n| {
+            vec![InlineKeyboardButton::callback(
+                format!(
+                    "{} del {}",
+                    n.id,
+                    n.created_at.with_timezone(&Europe::Rome).format("%d/%m/%Y")
+                ),
+                CorrectionAction::Newsletter(n.id).to_callback_data(),
+            )]
+        })
+        .collect_vec();
+
+    bot.send_message(dialogue.chat_id(), "Quale newsletter vuoi correggere?")
+        .reply_markup(InlineKeyboardMarkup::new(buttons))
+        .await
+        .context("Unable to send newsletter choice")?;
+    dialogue.update(CorrectionState::ChooseNewsletter).await?;
+
+    Ok(())
+}
+
+/// Starts a correction of the given newsletter, asking which program to correct
+pub async fn start_for_newsletter(
+    bot: &Bot,
+    dialogue: &CorrectionDialogue,
+    state: &ServerState,
+    newsletter_id: i32,
+) -> anyhow::Result<()> {
+    let programs = entity::program::Entity::find()
+        .filter(entity::program::Column::NewsletterId.eq(newsletter_id))
+        .order_by_asc(entity::program::Column::Id)
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch programs")?;
+
+    let mut buttons = programs
+        .into_iter()
+        .map(|p| {
+            vec![InlineKeyboardButton::callback(
+                p.title,
+                CorrectionAction::Program(p.id).to_callback_data(),
+            )]
+        })
+        .collect_vec();
+    buttons.push(vec![InlineKeyboardButton::callback(
+        "✅ Fine",
+        CorrectionAction::Done.to_callback_data(),
+    )]);
+
+    bot.send_message(
+        dialogue.chat_id(),
+        format!(
+            "Quale film della newsletter {} vuoi correggere? Premi ✅ Fine per aggiornare il messaggio.",
+            newsletter_id
+        ),
+    )
+    .reply_markup(InlineKeyboardMarkup::new(buttons))
+    .await
+    .context("Unable to send program choice")?;
+    dialogue
+        .update(CorrectionState::ChooseProgram { newsletter_id })
+        .await?;
+
+    Ok(())
+}
+
+async fn send_action_choice(
+    bot: &Bot,
+    dialogue: &CorrectionDialogue,
+    state: &ServerState,
+    newsletter_id: i32,
+    program_id: i32,
+) -> anyhow::Result<()> {
+    let program = entity::program::Entity::find_by_id(program_id)
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch program")?
+        .ok_or(anyhow!("No program with id {}", program_id))?;
+    let entries = program
+        .find_related(entity::entry::Entity)
+        .order_by_asc(entity::entry::Column::Date)
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch program entries")?;
+
+    let mut buttons = vec![vec![
+        InlineKeyboardButton::callback("✏️ Titolo", CorrectionAction::Title.to_callback_data()),
+        InlineKeyboardButton::callback(
+            "➕ Proiezione",
+            CorrectionAction::AddScreening.to_callback_data(),
+        ),
+    ]];
+    for entry in &entries {
+        let date = entry.date.with_timezone(&Europe::Rome);
+        buttons.push(vec![
+            InlineKeyboardButton::callback(
+                format!("✏️ {}", format_screening(date, entry.details.as_deref())),
+                CorrectionAction::EditScreening(entry.id).to_callback_data(),
+            ),
+            InlineKeyboardButton::callback(
+                "🗑",
+                CorrectionAction::RemoveScreening(entry.id).to_callback_data(),
+            ),
+        ]);
+    }
+    buttons.push(vec![InlineKeyboardButton::callback(
+        "⬅️ Indietro",
+        CorrectionAction::Back.to_callback_data(),
+    )]);
+
+    bot.send_message(
+        dialogue.chat_id(),
+        format!("Cosa vuoi correggere di {}?", program.title),
+    )
+    .reply_markup(InlineKeyboardMarkup::new(buttons))
+    .await
+    .context("Unable to send action choice")?;
+    dialogue
+        .update(CorrectionState::ChooseAction {
+            newsletter_id,
+            program_id,
+        })
+        .await?;
+
+    Ok(())
+}
+
+fn format_screening(date: DateTime<Tz>, details: Option<&str>) -> String {
+    match details {
+        Some(details) => format!("{} {}", date.format("%d/%m %H:%M"), details),
+        None => date.format("%d/%m %H:%M").to_string(),
+    }
+}
+
+async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: CorrectionDialogue,
+    correction: CorrectionState,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let action = CorrectionAction::from_callback_data(q.data.as_deref().unwrap_or_default())?;
+    bot.answer_callback_query(q.id).await?;
+
+    match (action, correction) {
+        (CorrectionAction::Newsletter(newsletter_id), CorrectionState::ChooseNewsletter) => {
+            start_for_newsletter(&bot, &dialogue, &state, newsletter_id).await?;
+        }
+        (
+            CorrectionAction::Program(program_id),
+            CorrectionState::ChooseProgram { newsletter_id },
+        ) => {
+            send_action_choice(&bot, &dialogue, &state, newsletter_id, program_id).await?;
+        }
+        (CorrectionAction::Done, CorrectionState::ChooseProgram { newsletter_id }) => {
+            dialogue.exit().await?;
+            let outcome = match rerender_newsletter(state.clone(), newsletter_id).await {
+                Ok(outcome) => outcome.to_string(),
+                Err(e) => format!("Errore: {:#}", e),
+            };
+            bot.send_message(dialogue.chat_id(), outcome)
+                .await
+                .context("Unable to send correction outcome")?;
+        }
+        (
+            CorrectionAction::Back,
+            CorrectionState::ChooseAction { newsletter_id, .. }
+            | CorrectionState::ReceiveTitle { newsletter_id, .. }
+            | CorrectionState::ReceiveScreening { newsletter_id, .. },
+        ) => {
+            start_for_newsletter(&bot, &dialogue, &state, newsletter_id).await?;
+        }
+        (
+            CorrectionAction::Title,
+            CorrectionState::ChooseAction {
+                newsletter_id,
+                program_id,
+            },
+        ) => {
+            ask_for_text(&bot, &dialogue, "Scrivi il nuovo titolo").await?;
+            dialogue
+                .update(CorrectionState::ReceiveTitle {
+                    newsletter_id,
+                    program_id,
+                })
+                .await?;
+        }
+        (
+            CorrectionAction::AddScreening,
+            CorrectionState::ChooseAction {
+                newsletter_id,
+                program_id,
+            },
+        ) => {
+            ask_for_screening(&bot, &dialogue).await?;
+            dialogue
+                .update(CorrectionState::ReceiveScreening {
+                    newsletter_id,
+                    program_id,
+                    entry_id: None,
+                })
+                .await?;
+        }
+        (
+            CorrectionAction::EditScreening(entry_id),
+            CorrectionState::ChooseAction {
+                newsletter_id,
+                program_id,
+            },
+        ) => {
+            ask_for_screening(&bot, &dialogue).await?;
+            dialogue
+                .update(CorrectionState::ReceiveScreening {
+                    newsletter_id,
+                    program_id,
+                    entry_id: Some(entry_id),
+                })
+                .await?;
+        }
+        (
+            CorrectionAction::RemoveScreening(entry_id),
+            CorrectionState::ChooseAction {
+                newsletter_id,
+                program_id,
+            },
+        ) => {
+            entity::entry::Entity::delete_many()
+                .filter(entity::entry::Column::Id.eq(entry_id))
+                .filter(entity::entry::Column::ProgramId.eq(program_id))
+                .exec(&state.db_connection)
+                .await
+                .context("Unable to delete entry")?;
+            info!("Removed entry {} of program {}", entry_id, program_id);
+
+            send_action_choice(&bot, &dialogue, &state, newsletter_id, program_id).await?;
+        }
+        _ => {
+            bot.send_message(
+                dialogue.chat_id(),
+                "Questa scelta non è più valida, usa /correggi per ricominciare",
+            )
+            .await
+            .context("Unable to send invalid choice message")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks for a text, as a forced reply so that it is received even by bots with privacy mode
+async fn ask_for_text(bot: &Bot, dialogue: &CorrectionDialogue, text: &str) -> anyhow::Result<()> {
+    bot.send_message(dialogue.chat_id(), text)
+        .reply_markup(ForceReply::new())
+        .await
+        .context("Unable to ask for text")?;
+
+    Ok(())
+}
+
+async fn ask_for_screening(bot: &Bot, dialogue: &CorrectionDialogue) -> anyhow::Result<()> {
+    ask_for_text(
+        bot,
+        dialogue,
+        "Scrivi data e ora della proiezione, seguite da eventuali dettagli, \
+        ad esempio: 25/09 21:15 versione originale con sottotitoli",
+    )
+    .await
+}
+
+async fn receive_text(
+    bot: Bot,
+    text: String,
+    dialogue: CorrectionDialogue,
+    correction: CorrectionState,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    match correction {
+        CorrectionState::ReceiveTitle {
+            newsletter_id,
+            program_id,
+        } => {
+            let title = text.trim();
+            if title.is_empty() {
+                return ask_for_text(&bot, &dialogue, "Il titolo non può essere vuoto").await;
+            }
+
+            entity::program::ActiveModel {
+                id: ActiveValue::Unchanged(program_id),
+                title: ActiveValue::Set(title.to_string()),
+                ..Default::default()
+            }
+            .update(&state.db_connection)
+            .await
+            .context("Unable to update program title")?;
+            info!("Renamed program {} to '{}'", program_id, title);
+
+            send_action_choice(&bot, &dialogue, &state, newsletter_id, program_id).await?;
+        }
+        CorrectionState::ReceiveScreening {
+            newsletter_id,
+            program_id,
+            entry_id,
+        } => {
+            let newsletter = entity::newsletter::Entity::find_by_id(newsletter_id)
+                .one(&state.db_connection)
+                .await
+                .context("Unable to fetch newsletter")?
+                .ok_or(anyhow!("No newsletter with id {}", newsletter_id))?;
+            let reference = newsletter.created_at.with_timezone(&Europe::Rome);
+
+            let Ok((date, details)) = parse_screening(&text, reference) else {
+                return ask_for_screening(&bot, &dialogue).await;
+            };
+
+            match entry_id {
+                // the entry must still belong to the program, in case the button was stale
+                Some(entry_id) => {
+                    entity::entry::Entity::update_many()
+                        .col_expr(
+                            entity::entry::Column::Date,
+                            Expr::value(date.fixed_offset()),
+                        )
+                        .col_expr(entity::entry::Column::Details, Expr::value(details))
+                        .filter(entity::entry::Column::Id.eq(entry_id))
+                        .filter(entity::entry::Column::ProgramId.eq(program_id))
+                        .exec(&state.db_connection)
+                        .await
+                        .context("Unable to update entry")?;
+                }
+                None => {
+                    entity::entry::ActiveModel {
+                        id: ActiveValue::NotSet,
+                        program_id: ActiveValue::Set(program_id),
+                        date: ActiveValue::Set(date.fixed_offset()),
+                        details: ActiveValue::Set(details),
+                    }
+                    .insert(&state.db_connection)
+                    .await
+                    .context("Unable to save entry")?;
+                }
+            }
+            info!("Saved screening on {} for program {}", date, program_id);
+
+            send_action_choice(&bot, &dialogue, &state, newsletter_id, program_id).await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Parses a screening written as `dd/mm HH:MM [details]`, picking the year that puts it closest
+/// to `reference`
+fn parse_screening(
+    text: &str,
+    reference: DateTime<Tz>,
+) -> anyhow::Result<(DateTime<Tz>, Option<String>)> {
+    let mut parts = text.split_whitespace();
+    let (day, month) = parts
+        .next()
+        .and_then(|d| d.split_once('/'))
+        .ok_or(anyhow!("Missing date in '{}'", text))?;
+    let time = parts.next().ok_or(anyhow!("Missing time in '{}'", text))?;
+    let time = NaiveTime::parse_from_str(&time.replace('.', ":"), "%H:%M")
+        .with_context(|| format!("Invalid time in '{}'", text))?;
+    let details = Some(parts.join(" ")).filter(|d| !d.is_empty());
+
+    let (day, month) = (u32::from_str(day)?, u32::from_str(month)?);
+    let date = [reference.year() - 1, reference.year(), reference.year() + 1]
+        .into_iter()
+        .filter_map(|year| NaiveDate::from_ymd_opt(year, month, day))
+        .filter_map(|date| {
+            Europe::Rome
+                .from_local_datetime(&date.and_time(time))
+                .earliest()
+        })
+        .min_by_key(|date| (*date - reference).num_seconds().abs())
+        .ok_or(anyhow!("Invalid date in '{}'", text))?;
+
+    Ok((date, details))
+}
+
+/// Updates the messages of a corrected newsletter, either in the channel or in its preview,
+/// returning a description of the outcome
+async fn rerender_newsletter(
+    state: Arc<ServerState>,
+    newsletter_id: i32,
+) -> anyhow::Result<&'static str> {
+    let newsletter = entity::newsletter::Entity::find_by_id(newsletter_id)
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch newsletter")?
+        .ok_or(anyhow!("No newsletter with id {}", newsletter_id))?;
+
+    if newsletter.message_id.is_none() {
+        if newsletter.preview_message_id.is_some() {
+            approval::update_preview(&state, &newsletter).await?;
+            return Ok("Anteprima della newsletter aggiornata");
+        }
+        return Ok("La newsletter non è stata pubblicata, nessun messaggio da aggiornare");
+    }
+
+    // the latest newsletter also determines when the next update is scheduled
+    let latest_newsletter_id = fetch_latest_newsletter(&state.db_connection)
+        .await
+        .map(|(_, latest_newsletter)| latest_newsletter.id)
+        .ok();
+    if latest_newsletter_id == Some(newsletter_id) {
+        update_latest_newsletter(state).await?;
+    } else {
+        let newsletter_entry = fetch_newsletter_entry(&state.db_connection, &newsletter).await?;
+        for target in &state.targets {
+            messages::edit_newsletter_messages(
+                &state,
+                target,
+                newsletter_id,
+                &make_messages(&state, target, &newsletter_entry)?,
+            )
+            .await?;
+        }
+    }
+
+    Ok("Messaggio della newsletter aggiornato")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use chrono_tz::Europe;
+
+    use crate::corrections::parse_screening;
+
+    #[test]
+    fn screenings_are_parsed_close_to_the_newsletter() {
+        let reference = DateTime::parse_from_rfc3339("2024-12-28T10:00:00+01:00")
+            .unwrap()
+            .with_timezone(&Europe::Rome);
+
+        assert_eq!(
+            parse_screening("2/1 21.15 versione originale", reference).unwrap(),
+            (
+                DateTime::parse_from_rfc3339("2025-01-02T21:15:00+01:00")
+                    .unwrap()
+                    .with_timezone(&Europe::Rome),
+                Some("versione originale".to_string())
+            )
+        );
+        assert_eq!(
+            parse_screening("30/12 17:00", reference).unwrap(),
+            (
+                DateTime::parse_from_rfc3339("2024-12-30T17:00:00+01:00")
+                    .unwrap()
+                    .with_timezone(&Europe::Rome),
+                None
+            )
+        );
+        assert!(parse_screening("domani", reference).is_err());
+    }
+}
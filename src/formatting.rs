@@ -0,0 +1,54 @@
+use chrono::Timelike;
+
+/// Screenings starting from this hour are marked as late shows
+const LATE_SHOW_HOUR: u32 = 22;
+
+/// Clock face emoji showing `time` rounded to the nearest half hour, from 🕐 to 🕧
+pub fn clock_emoji(time: &impl Timelike) -> char {
+    // half hours since midnight, on a 12-hour clock face
+    let half_hours = ((time.hour() * 60 + time.minute() + 15) / 30) % 24;
+    let hour = match half_hours / 2 {
+        0 => 12,
+        hour => hour,
+    };
+
+    // 🕐..🕛 are the hours from one to twelve o'clock, followed by 🕜..🕧 for the half hours
+    let offset = (hour - 1) + if half_hours % 2 == 1 { 12 } else { 0 };
+    char::from_u32(0x1F550 + offset).unwrap_or('🕔')
+}
+
+/// Whether a screening starting at `time` is a late show, marked with 🌙
+pub fn is_late_show(time: &impl Timelike) -> bool {
+    time.hour() >= LATE_SHOW_HOUR
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use crate::formatting::{clock_emoji, is_late_show};
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn clock_emoji_is_rounded_to_the_nearest_half_hour() {
+        assert_eq!(clock_emoji(&time(17, 0)), '🕔');
+        assert_eq!(clock_emoji(&time(18, 45)), '🕖');
+        assert_eq!(clock_emoji(&time(18, 44)), '🕡');
+        assert_eq!(clock_emoji(&time(21, 15)), '🕤');
+        assert_eq!(clock_emoji(&time(0, 10)), '🕛');
+        assert_eq!(clock_emoji(&time(12, 30)), '🕧');
+        assert_eq!(clock_emoji(&time(23, 50)), '🕛');
+        assert_eq!(clock_emoji(&time(1, 0)), '🕐');
+        assert_eq!(clock_emoji(&time(13, 29)), '🕜');
+    }
+
+    #[test]
+    fn late_shows_start_from_ten_pm() {
+        assert!(!is_late_show(&time(21, 59)));
+        assert!(is_late_show(&time(22, 0)));
+        assert!(is_late_show(&time(23, 30)));
+    }
+}
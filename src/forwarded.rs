@@ -0,0 +1,267 @@
+use itertools::Itertools;
+use scraper::{Html, Node};
+
+use crate::inbound::InboundEmail;
+
+/// Lines opening the forwarded message block, as written by Gmail and Apple Mail (English and Italian)
+const FORWARD_MARKERS: [&str; 4] = [
+    "---------- Forwarded message ---------",
+    "---------- Messaggio inoltrato ---------",
+    "Begin forwarded message:",
+    "Inizio messaggio inoltrato:",
+];
+
+const FROM_HEADERS: [&str; 2] = ["From:", "Da:"];
+const SUBJECT_HEADERS: [&str; 2] = ["Subject:", "Oggetto:"];
+/// Other headers of the original email, which are skipped
+const OTHER_HEADERS: [&str; 8] = [
+    "Date:",
+    "Data:",
+    "To:",
+    "A:",
+    "Cc:",
+    "Reply-To:",
+    "Rispondi a:",
+    "Rispondi-a:",
+];
+
+/// Elements that have no closing tag
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Extracts the original email from a manually forwarded one.
+///
+/// The forwarded block starts with one of [`FORWARD_MARKERS`], followed by the original headers
+/// (one per line, either split by `<br>` as in Gmail or in sibling elements as in Apple Mail),
+/// and then by the original HTML body.
+/// Returns `None` if the email doesn't look like a forward, or the original headers can't be found.
+pub fn unwrap_forwarded_email(email: &InboundEmail) -> Option<InboundEmail> {
+    let (marker_index, marker) = FORWARD_MARKERS
+        .iter()
+        .filter_map(|m| email.html_body.find(m).map(|i| (i, m)))
+        .min_by_key(|(i, _)| *i)?;
+
+    let headers_start = marker_index + marker.len();
+    let after_marker = &email.html_body[headers_start..];
+    let line_ends = tags(after_marker)
+        .into_iter()
+        .filter(Tag::ends_line)
+        .map(|tag| tag.end)
+        .chain(std::iter::once(after_marker.len()));
+
+    let (mut from, mut subject) = (None, None);
+    let mut line_start = 0;
+    let mut headers_end = 0;
+    for line_end in line_ends {
+        let line = html_to_text(&after_marker[line_start..line_end]);
+        let line = line.trim();
+        line_start = line_end;
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(value) = header_value(line, &FROM_HEADERS) {
+            from = Some(value);
+        } else if let Some(value) = header_value(line, &SUBJECT_HEADERS) {
+            subject = Some(value);
+        } else if header_value(line, &OTHER_HEADERS).is_none() {
+            // First line of the original body
+            break;
+        }
+
+        headers_end = line_end;
+    }
+
+    Some(InboundEmail {
+        from: from?,
+        subject: subject?,
+        html_body: strip_unbalanced_closing_tags(&after_marker[headers_end..]),
+        attachments: email.attachments.clone(),
+    })
+}
+
+fn header_value(line: &str, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| line.strip_prefix(name))
+        .map(|value| value.trim().to_string())
+}
+
+fn html_to_text(html: &str) -> String {
+    Html::parse_fragment(html)
+        .root_element()
+        .descendants()
+        .filter_map(|d| match d.value() {
+            Node::Text(t) => Some(t.to_string()),
+            Node::Element(e) if &e.name.local == "br" => Some(String::from("\n")),
+            _ => None,
+        })
+        .join("")
+}
+
+/// An HTML tag, with its byte range in the source
+struct Tag {
+    start: usize,
+    end: usize,
+    /// Lowercase element name
+    name: String,
+    closing: bool,
+    self_closing: bool,
+}
+
+impl Tag {
+    /// Whether the tag ends a line of text, like a line break or the end of a block
+    fn ends_line(&self) -> bool {
+        match self.name.as_str() {
+            "br" => !self.closing,
+            "div" | "p" => self.closing,
+            _ => false,
+        }
+    }
+}
+
+/// Lists the tags of an HTML fragment, skipping comments and doctypes.
+fn tags(html: &str) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut position = 0;
+
+    while let Some(offset) = html[position..].find('<') {
+        let start = position + offset;
+        if html[start..].starts_with("<!--") {
+            match html[start..].find("-->") {
+                Some(length) => position = start + length + "-->".len(),
+                None => break,
+            }
+            continue;
+        }
+
+        let Some(length) = html[start..].find('>') else {
+            break;
+        };
+        let end = start + length + 1;
+        position = end;
+
+        let inner = &html[start + 1..end - 1];
+        let closing = inner.starts_with('/');
+        let name = inner
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            continue;
+        }
+
+        tags.push(Tag {
+            start,
+            end,
+            name: name.to_ascii_lowercase(),
+            closing,
+            self_closing: inner.ends_with('/'),
+        });
+    }
+
+    tags
+}
+
+/// Removes the closing tags without a matching opening tag, left over from the elements that
+/// enclose the forwarded block.
+fn strip_unbalanced_closing_tags(html: &str) -> String {
+    let mut open_elements: Vec<String> = Vec::new();
+    let mut result = String::with_capacity(html.len());
+    let mut copied = 0;
+
+    for tag in tags(html) {
+        if tag.closing {
+            match open_elements.iter().rposition(|name| *name == tag.name) {
+                Some(position) => open_elements.truncate(position),
+                None => {
+                    result.push_str(&html[copied..tag.start]);
+                    copied = tag.end;
+                }
+            }
+        } else if !tag.self_closing && !VOID_ELEMENTS.contains(&tag.name.as_str()) {
+            open_elements.push(tag.name);
+        }
+    }
+    result.push_str(&html[copied..]);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forwarded::unwrap_forwarded_email;
+    use crate::inbound::InboundEmail;
+
+    #[test]
+    fn gmail_forward_is_unwrapped() {
+        let email = InboundEmail {
+            from: "Mario Rossi <mario.rossi@example.com>".to_string(),
+            subject: "Fwd: Spazio Alfieri • programmazione 25 settembre > 2 ottobre".to_string(),
+            html_body: r#"<div dir="ltr"><br><div class="gmail_quote"><div dir="ltr" class="gmail_attr">---------- Forwarded message ---------<br>From: <strong class="gmail_sendername" dir="auto">Spazio Alfieri</strong> <span dir="auto">&lt;<a href="mailto:newsletter@spazioalfieri.it">newsletter@spazioalfieri.it</a>&gt;</span><br>Date: mar 24 set 2024 alle ore 10:00<br>Subject: Spazio Alfieri • programmazione 25 settembre &gt; 2 ottobre<br>To: &lt;<a href="mailto:mario.rossi@example.com">mario.rossi@example.com</a>&gt;<br></div><br><br><table><tr><td>Programmazione</td></tr></table></div></div>"#.to_string(),
+            attachments: Vec::new(),
+        };
+
+        let original = unwrap_forwarded_email(&email).unwrap();
+
+        assert_eq!(
+            original,
+            InboundEmail {
+                from: "Spazio Alfieri <newsletter@spazioalfieri.it>".to_string(),
+                subject: "Spazio Alfieri • programmazione 25 settembre > 2 ottobre".to_string(),
+                html_body: "<br><br><table><tr><td>Programmazione</td></tr></table>".to_string(),
+                attachments: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn apple_mail_forward_is_unwrapped() {
+        let email = InboundEmail {
+            from: "Mario Rossi <mario.rossi@example.com>".to_string(),
+            subject: "Fwd: Spazio Alfieri • programmazione 25 settembre > 2 ottobre".to_string(),
+            html_body: r#"<html><body style="overflow-wrap: break-word;"><br><div><br><blockquote type="cite"><div>Begin forwarded message:</div><br class="Apple-interchange-newline"><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>From: </b></span><span style="font-family: -webkit-system-font;">Spazio Alfieri &lt;newsletter@spazioalfieri.it&gt;<br></span></div><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>Subject: </b></span><span style="font-family: -webkit-system-font;"><b>Spazio Alfieri • programmazione 25 settembre &gt; 2 ottobre</b><br></span></div><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>Date: </b></span><span style="font-family: -webkit-system-font;">24 September 2024 at 10:00:00 CEST<br></span></div><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>To: </b></span><span style="font-family: -webkit-system-font;">mario.rossi@example.com<br></span></div><br><div><table><tr><td>Programmazione</td></tr></table></div></blockquote></div><br></body></html>"#.to_string(),
+            attachments: Vec::new(),
+        };
+
+        let original = unwrap_forwarded_email(&email).unwrap();
+
+        assert_eq!(
+            original,
+            InboundEmail {
+                from: "Spazio Alfieri <newsletter@spazioalfieri.it>".to_string(),
+                subject: "Spazio Alfieri • programmazione 25 settembre > 2 ottobre".to_string(),
+                html_body: "<br><div><table><tr><td>Programmazione</td></tr></table></div><br>"
+                    .to_string(),
+                attachments: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn italian_apple_mail_forward_is_unwrapped() {
+        let email = InboundEmail {
+            from: "Mario Rossi <mario.rossi@example.com>".to_string(),
+            subject: "I: Spazio Alfieri • programmazione 25 settembre > 2 ottobre".to_string(),
+            html_body: r#"<html><body style="overflow-wrap: break-word;"><br><div><br><blockquote type="cite"><div>Inizio messaggio inoltrato:</div><br class="Apple-interchange-newline"><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>Da: </b></span><span style="font-family: -webkit-system-font;">Spazio Alfieri &lt;newsletter@spazioalfieri.it&gt;<br></span></div><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>Oggetto: </b></span><span style="font-family: -webkit-system-font;"><b>Spazio Alfieri • programmazione 25 settembre &gt; 2 ottobre</b><br></span></div><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>Data: </b></span><span style="font-family: -webkit-system-font;">24 settembre 2024 alle ore 10:00:00 CEST<br></span></div><div style="margin: 0px;"><span style="font-family: -webkit-system-font;"><b>A: </b></span><span style="font-family: -webkit-system-font;">mario.rossi@example.com<br></span></div><br><div><table><tr><td>Programmazione</td></tr></table></div></blockquote></div><br></body></html>"#.to_string(),
+            attachments: Vec::new(),
+        };
+
+        let original = unwrap_forwarded_email(&email).unwrap();
+
+        assert_eq!(
+            original,
+            InboundEmail {
+                from: "Spazio Alfieri <newsletter@spazioalfieri.it>".to_string(),
+                subject: "Spazio Alfieri • programmazione 25 settembre > 2 ottobre".to_string(),
+                html_body: "<br><div><table><tr><td>Programmazione</td></tr></table></div><br>"
+                    .to_string(),
+                attachments: Vec::new(),
+            }
+        );
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use futures::future::BoxFuture;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{error, info};
+
+use crate::inbound::EmailError;
+
+/// Servers drop idling clients after 30 minutes, so IDLE is restarted before that
+const MAX_IDLE_DURATION: Duration = Duration::from_secs(29 * 60);
+
+/// How many times a message failing with a transient error is handled before giving up on it
+const MAX_TRANSIENT_ATTEMPTS: u32 = 5;
+
+/// Configuration of the optional IMAP polling ingestion mode, enabled by setting `IMAP_HOST`.
+pub struct ImapConfig {
+    host: String,
+    port: u16,
+    tls: bool,
+    username: String,
+    password: String,
+    mailbox: String,
+    poll_interval: Duration,
+}
+
+impl ImapConfig {
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(host) = std::env::var("IMAP_HOST") else {
+            return Ok(None);
+        };
+
+        let port = match std::env::var("IMAP_PORT") {
+            Ok(raw) => u16::from_str(&raw)
+                .with_context(|| format!("Unable to parse IMAP port '{}' into u16", raw))?,
+            Err(_) => 993,
+        };
+
+        let tls = match std::env::var("IMAP_TLS") {
+            Ok(raw) => bool::from_str(&raw)
+                .with_context(|| format!("Unable to parse IMAP_TLS '{}' into bool", raw))?,
+            Err(_) => true,
+        };
+
+        let username = std::env::var("IMAP_USERNAME")
+            .context("Unable to read IMAP_USERNAME environment variable")?;
+        let password = std::env::var("IMAP_PASSWORD")
+            .context("Unable to read IMAP_PASSWORD environment variable")?;
+        let mailbox = std::env::var("IMAP_MAILBOX").unwrap_or_else(|_| "INBOX".to_string());
+
+        let poll_interval = match std::env::var("IMAP_POLL_INTERVAL_SECS") {
+            Ok(raw) => Duration::from_secs(u64::from_str(&raw).with_context(|| {
+                format!("Unable to parse IMAP poll interval '{}' into u64", raw)
+            })?),
+            Err(_) => Duration::from_secs(5 * 60),
+        };
+
+        Ok(Some(ImapConfig {
+            host,
+            port,
+            tls,
+            username,
+            password,
+            mailbox,
+            poll_interval,
+        }))
+    }
+}
+
+pub trait ImapStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ImapStream for S {}
+
+/// A single server response, with literals (`{n}` byte counts followed by raw data) split from its text.
+#[derive(Debug)]
+struct ImapResponse {
+    text: String,
+    literals: Vec<Vec<u8>>,
+}
+
+/// The part of a response read so far, kept in the session so that a cancelled read (e.g. when
+/// IDLE times out) can be resumed without losing bytes.
+#[derive(Debug, Default)]
+struct PartialResponse {
+    text: String,
+    literals: Vec<Vec<u8>>,
+    line: Vec<u8>,
+    /// Literal being read, along with its size
+    literal: Option<(Vec<u8>, usize)>,
+}
+
+/// A minimal IMAP4rev1 client, implementing just the commands needed to fetch newsletters.
+pub struct ImapSession<S> {
+    stream: BufReader<S>,
+    next_tag: u32,
+    capabilities: Vec<String>,
+    partial_response: PartialResponse,
+}
+
+impl<S: ImapStream> ImapSession<S> {
+    pub async fn new(stream: S) -> anyhow::Result<Self> {
+        let mut session = ImapSession {
+            stream: BufReader::new(stream),
+            next_tag: 1,
+            capabilities: Vec::new(),
+            partial_response: PartialResponse::default(),
+        };
+
+        let greeting = session
+            .read_response()
+            .await
+            .context("Unable to read server greeting")?;
+        if !greeting.text.starts_with("* OK") {
+            bail!("Unexpected server greeting: {}", greeting.text);
+        }
+
+        let capabilities = session.command("CAPABILITY").await?;
+        session.capabilities = capabilities
+            .iter()
+            .filter_map(|r| r.text.strip_prefix("* CAPABILITY "))
+            .flat_map(|c| c.split_whitespace())
+            .map(str::to_uppercase)
+            .collect();
+
+        Ok(session)
+    }
+
+    pub async fn login(&mut self, username: &str, password: &str) -> anyhow::Result<()> {
+        self.command(&format!("LOGIN {} {}", quote(username), quote(password)))
+            .await
+            .context("Unable to log in")?;
+
+        Ok(())
+    }
+
+    pub async fn select(&mut self, mailbox: &str) -> anyhow::Result<()> {
+        self.command(&format!("SELECT {}", quote(mailbox)))
+            .await
+            .with_context(|| format!("Unable to select mailbox '{}'", mailbox))?;
+
+        Ok(())
+    }
+
+    pub fn supports_idle(&self) -> bool {
+        self.capabilities.iter().any(|c| c == "IDLE")
+    }
+
+    /// Returns the UIDs of unseen messages sent by any of `senders`
+    pub async fn search_unseen(&mut self, senders: &HashSet<String>) -> anyhow::Result<Vec<u32>> {
+        let mut uids = BTreeSet::new();
+
+        for sender in senders {
+            let responses = self
+                .command(&format!("UID SEARCH UNSEEN FROM {}", quote(sender)))
+                .await
+                .with_context(|| format!("Unable to search messages from '{}'", sender))?;
+
+            for response in responses {
+                if let Some(found) = response.text.strip_prefix("* SEARCH") {
+                    for uid in found.split_whitespace() {
+                        uids.insert(
+                            u32::from_str(uid).with_context(|| {
+                                format!("Invalid UID in search response: {}", uid)
+                            })?,
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(uids.into_iter().collect())
+    }
+
+    /// Fetches the full RFC 822 message without setting the `\Seen` flag
+    pub async fn fetch(&mut self, uid: u32) -> anyhow::Result<Vec<u8>> {
+        self.command(&format!("UID FETCH {} BODY.PEEK[]", uid))
+            .await
+            .with_context(|| format!("Unable to fetch message {}", uid))?
+            .into_iter()
+            .find(|r| r.text.contains("FETCH"))
+            .and_then(|r| r.literals.into_iter().next())
+            .ok_or(anyhow!(
+                "No message body in fetch response for message {}",
+                uid
+            ))
+    }
+
+    pub async fn mark_seen(&mut self, uid: u32) -> anyhow::Result<()> {
+        self.command(&format!("UID STORE {} +FLAGS (\\Seen)", uid))
+            .await
+            .with_context(|| format!("Unable to mark message {} as seen", uid))?;
+
+        Ok(())
+    }
+
+    /// Waits until the server notifies a new message, or `timeout` has elapsed
+    pub async fn idle(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        let tag = self.write_command("IDLE").await?;
+
+        let continuation = self.read_response().await?;
+        if !continuation.text.starts_with('+') {
+            bail!("Server refused to idle: {}", continuation.text);
+        }
+
+        let wait_for_message = async {
+            loop {
+                let response = self.read_response().await?;
+                if response.text.ends_with("EXISTS") {
+                    return Ok::<(), anyhow::Error>(());
+                }
+            }
+        };
+        // `read_response` is cancellation safe, so a response cut by the timeout is completed
+        // while reading up to the tagged reply to DONE
+        match tokio::time::timeout(timeout, wait_for_message).await {
+            Ok(result) => result?,
+            Err(_) => info!("IMAP idle timed out"),
+        }
+
+        self.stream.get_mut().write_all(b"DONE\r\n").await?;
+        self.stream.get_mut().flush().await?;
+        self.read_until_tagged(&tag).await?;
+
+        Ok(())
+    }
+
+    async fn command(&mut self, command: &str) -> anyhow::Result<Vec<ImapResponse>> {
+        let tag = self.write_command(command).await?;
+        self.read_until_tagged(&tag).await
+    }
+
+    async fn write_command(&mut self, command: &str) -> anyhow::Result<String> {
+        let tag = format!("A{:04}", self.next_tag);
+        self.next_tag += 1;
+
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{} {}\r\n", tag, command).as_bytes())
+            .await
+            .context("Unable to send IMAP command")?;
+        stream.flush().await?;
+
+        Ok(tag)
+    }
+
+    async fn read_until_tagged(&mut self, tag: &str) -> anyhow::Result<Vec<ImapResponse>> {
+        let mut untagged = Vec::new();
+
+        loop {
+            let response = self.read_response().await?;
+            if let Some(status) = response.text.strip_prefix(tag) {
+                let status = status.trim_start();
+                if status.starts_with("OK") {
+                    return Ok(untagged);
+                }
+
+                bail!("IMAP command failed: {}", status);
+            }
+
+            untagged.push(response);
+        }
+    }
+
+    /// Reads the next response.
+    ///
+    /// This is cancellation safe: the bytes read before the future is dropped are kept in
+    /// the session, and the next call continues from them.
+    async fn read_response(&mut self) -> anyhow::Result<ImapResponse> {
+        loop {
+            if let Some((literal, size)) = &mut self.partial_response.literal {
+                while literal.len() < *size {
+                    let available = self
+                        .stream
+                        .fill_buf()
+                        .await
+                        .context("Unable to read IMAP literal")?;
+                    if available.is_empty() {
+                        bail!("IMAP connection closed by server");
+                    }
+
+                    let count = available.len().min(*size - literal.len());
+                    literal.extend_from_slice(&available[..count]);
+                    self.stream.consume(count);
+                }
+
+                let (literal, _) = self
+                    .partial_response
+                    .literal
+                    .take()
+                    .expect("literal is being read");
+                self.partial_response.literals.push(literal);
+            }
+
+            self.stream
+                .read_until(b'\n', &mut self.partial_response.line)
+                .await
+                .context("Unable to read IMAP response")?;
+            if !self.partial_response.line.ends_with(b"\n") {
+                bail!("IMAP connection closed by server");
+            }
+
+            let line = std::mem::take(&mut self.partial_response.line);
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            let literal_size = line
+                .strip_suffix('}')
+                .and_then(|l| l.rsplit_once('{'))
+                .and_then(|(_, size)| usize::from_str(size).ok());
+
+            self.partial_response.text.push_str(line);
+
+            match literal_size {
+                Some(size) => {
+                    self.partial_response.literal = Some((Vec::with_capacity(size), size));
+                }
+                None => {
+                    let PartialResponse { text, literals, .. } =
+                        std::mem::take(&mut self.partial_response);
+                    return Ok(ImapResponse { text, literals });
+                }
+            }
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Polls the configured mailbox forever, passing every unseen message from `allowed_senders`
+/// to `handle_message` as raw RFC 822 bytes.
+///
+/// Messages are marked as seen once handled, unless handling failed with a transient error,
+/// in which case they are retried on the next poll, up to [`MAX_TRANSIENT_ATTEMPTS`] times.
+pub async fn poll_mailbox<F>(
+    config: ImapConfig,
+    allowed_senders: HashSet<String>,
+    handle_message: F,
+) where
+    F: Fn(Vec<u8>) -> BoxFuture<'static, Result<(), EmailError>>,
+{
+    info!("Starting IMAP polling of {}:{}", config.host, config.port);
+
+    let mut failed_attempts = HashMap::new();
+    loop {
+        if let Err(e) = run_session(
+            &config,
+            &allowed_senders,
+            &handle_message,
+            &mut failed_attempts,
+        )
+        .await
+        {
+            error!("IMAP session failed: {:#}", e);
+            tokio::time::sleep(config.poll_interval).await;
+        }
+    }
+}
+
+async fn connect(config: &ImapConfig) -> anyhow::Result<Box<dyn ImapStream>> {
+    let tcp_stream = TcpStream::connect((config.host.as_str(), config.port))
+        .await
+        .with_context(|| format!("Unable to connect to {}:{}", config.host, config.port))?;
+
+    if !config.tls {
+        return Ok(Box::new(tcp_stream));
+    }
+
+    let connector = tokio_native_tls::TlsConnector::from(
+        native_tls::TlsConnector::new().context("Unable to create TLS connector")?,
+    );
+    let tls_stream = connector
+        .connect(&config.host, tcp_stream)
+        .await
+        .context("Unable to establish TLS connection")?;
+
+    Ok(Box::new(tls_stream))
+}
+
+async fn run_session<F>(
+    config: &ImapConfig,
+    allowed_senders: &HashSet<String>,
+    handle_message: &F,
+    failed_attempts: &mut HashMap<u32, u32>,
+) -> anyhow::Result<()>
+where
+    F: Fn(Vec<u8>) -> BoxFuture<'static, Result<(), EmailError>>,
+{
+    let mut session = ImapSession::new(connect(config).await?).await?;
+    session.login(&config.username, &config.password).await?;
+    session.select(&config.mailbox).await?;
+
+    loop {
+        process_unseen_messages(
+            &mut session,
+            allowed_senders,
+            handle_message,
+            failed_attempts,
+        )
+        .await?;
+
+        if session.supports_idle() {
+            session
+                .idle(MAX_IDLE_DURATION.min(config.poll_interval.max(Duration::from_secs(60))))
+                .await?;
+        } else {
+            tokio::time::sleep(config.poll_interval).await;
+            // NOOP lets the server report messages received while sleeping
+            session.command("NOOP").await?;
+        }
+    }
+}
+
+/// Handles the unseen messages of the selected mailbox.
+///
+/// `failed_attempts` counts, by UID, how many times a message failed with a transient error.
+async fn process_unseen_messages<S, F>(
+    session: &mut ImapSession<S>,
+    allowed_senders: &HashSet<String>,
+    handle_message: &F,
+    failed_attempts: &mut HashMap<u32, u32>,
+) -> anyhow::Result<()>
+where
+    S: ImapStream,
+    F: Fn(Vec<u8>) -> BoxFuture<'static, Result<(), EmailError>>,
+{
+    let uids = session.search_unseen(allowed_senders).await?;
+    if !uids.is_empty() {
+        info!("Found {} unseen messages", uids.len());
+    }
+
+    for uid in uids {
+        let raw_message = session.fetch(uid).await?;
+
+        match handle_message(raw_message).await {
+            Err(EmailError::Transient(_)) => {
+                let attempts = failed_attempts.entry(uid).or_default();
+                *attempts += 1;
+
+                if *attempts < MAX_TRANSIENT_ATTEMPTS {
+                    info!("Leaving message {} unseen to retry it later", uid);
+                } else {
+                    error!(
+                        "Giving up on message {} after {} failed attempts",
+                        uid, attempts
+                    );
+                    failed_attempts.remove(&uid);
+                    session.mark_seen(uid).await?;
+                }
+            }
+            _ => {
+                failed_attempts.remove(&uid);
+                session.mark_seen(uid).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    use crate::imap::{
+        process_unseen_messages, ImapSession, PartialResponse, MAX_TRANSIENT_ATTEMPTS,
+    };
+    use crate::inbound::EmailError;
+
+    const MESSAGE: &str =
+        "From: newsletter@spazioalfieri.it\r\nSubject: programmazione\r\n\r\nbody\r\n";
+
+    /// Scripted stand-in for an IMAP server holding one unseen message with UID 7
+    async fn fake_server(stream: DuplexStream) -> Vec<String> {
+        let mut stream = BufReader::new(stream);
+        let mut commands = Vec::new();
+        stream.write_all(b"* OK IMAP4rev1 ready\r\n").await.unwrap();
+
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return commands;
+            }
+            let (tag, command) = line.trim_end().split_once(' ').unwrap();
+            commands.push(command.to_string());
+
+            let untagged = if command == "CAPABILITY" {
+                "* CAPABILITY IMAP4rev1 IDLE\r\n".to_string()
+            } else if command.starts_with("UID SEARCH") {
+                "* SEARCH 7\r\n".to_string()
+            } else if command.starts_with("UID FETCH 7") {
+                format!(
+                    "* 1 FETCH (UID 7 BODY[] {{{}}}\r\n{})\r\n",
+                    MESSAGE.len(),
+                    MESSAGE
+                )
+            } else {
+                String::new()
+            };
+
+            stream
+                .write_all(format!("{}{} OK done\r\n", untagged, tag).as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unseen_messages_are_fetched_and_marked_as_seen() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(fake_server(server));
+
+        let mut session = ImapSession::new(client).await.unwrap();
+        assert!(session.supports_idle());
+        session.login("bot", "pass\"word").await.unwrap();
+        session.select("INBOX").await.unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let allowed_senders = HashSet::from(["newsletter@spazioalfieri.it".to_string()]);
+        process_unseen_messages(
+            &mut session,
+            &allowed_senders,
+            &|raw_message| {
+                let received = received.clone();
+                Box::pin(async move {
+                    received.lock().unwrap().push(raw_message);
+                    Ok::<(), EmailError>(())
+                })
+            },
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        drop(session);
+
+        assert_eq!(*received.lock().unwrap(), vec![MESSAGE.as_bytes().to_vec()]);
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "CAPABILITY",
+                "LOGIN \"bot\" \"pass\\\"word\"",
+                "SELECT \"INBOX\"",
+                "UID SEARCH UNSEEN FROM \"newsletter@spazioalfieri.it\"",
+                "UID FETCH 7 BODY.PEEK[]",
+                "UID STORE 7 +FLAGS (\\Seen)",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn message_failing_transiently_is_given_up_after_max_attempts() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(fake_server(server));
+
+        let mut session = ImapSession::new(client).await.unwrap();
+        let allowed_senders = HashSet::from(["newsletter@spazioalfieri.it".to_string()]);
+        let mut failed_attempts = HashMap::new();
+        for _ in 0..MAX_TRANSIENT_ATTEMPTS {
+            process_unseen_messages(
+                &mut session,
+                &allowed_senders,
+                &|_| Box::pin(async { Err(EmailError::Transient(anyhow!("Telegram is down"))) }),
+                &mut failed_attempts,
+            )
+            .await
+            .unwrap();
+        }
+        drop(session);
+
+        let commands = server.await.unwrap();
+        let stores = commands
+            .iter()
+            .filter(|c| c.starts_with("UID STORE 7"))
+            .count();
+        assert_eq!(stores, 1);
+        assert_eq!(commands.last().unwrap(), "UID STORE 7 +FLAGS (\\Seen)");
+        assert!(failed_attempts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn response_read_is_resumed_after_cancellation() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut session = ImapSession {
+            stream: BufReader::new(client),
+            next_tag: 1,
+            capabilities: Vec::new(),
+            partial_response: PartialResponse::default(),
+        };
+
+        server
+            .write_all(b"* 1 FETCH (BODY[] {10}\r\nhello")
+            .await
+            .unwrap();
+        let timed_out =
+            tokio::time::timeout(Duration::from_millis(50), session.read_response()).await;
+        assert!(timed_out.is_err());
+
+        server.write_all(b"world)\r\n* 2 EXI").await.unwrap();
+        let timed_out =
+            tokio::time::timeout(Duration::from_millis(50), session.read_response()).await;
+        let fetch = timed_out.unwrap().unwrap();
+        assert_eq!(fetch.text, "* 1 FETCH (BODY[] {10})");
+        assert_eq!(fetch.literals, vec![b"helloworld".to_vec()]);
+
+        let timed_out =
+            tokio::time::timeout(Duration::from_millis(50), session.read_response()).await;
+        assert!(timed_out.is_err());
+
+        server.write_all(b"STS\r\n").await.unwrap();
+        let exists = session.read_response().await.unwrap();
+        assert_eq!(exists.text, "* 2 EXISTS");
+    }
+}
@@ -0,0 +1,866 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use axum::body::Bytes;
+use axum::extract::multipart::MultipartError;
+use axum::extract::{FromRequest, FromRequestParts, Multipart, Request};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Form, Json};
+use axum_auth::{AuthBasic, AuthBearer};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use mail_parser::{MessageParser, MimeHeaders};
+use serde::Deserialize;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+/// Errors raised while handling an inbound email.
+///
+/// Each variant maps to the status code that tells the provider whether to retry the delivery:
+/// Mailgun stops on `406 Not Acceptable` and retries on any other non-2xx code.
+#[derive(Debug)]
+pub enum EmailError {
+    /// The request could not be authenticated (bad signature or unknown sender)
+    Unauthorized(anyhow::Error),
+    /// The email can never be handled (e.g. the body cannot be parsed), retrying won't help
+    Permanent(anyhow::Error),
+    /// A failure of the database, Telegram or Crontap that may go away on retry
+    Transient(anyhow::Error),
+}
+
+impl EmailError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            EmailError::Unauthorized(_) | EmailError::Permanent(_) => StatusCode::NOT_ACCEPTABLE,
+            EmailError::Transient(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl Display for EmailError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailError::Unauthorized(e) | EmailError::Permanent(e) | EmailError::Transient(e) => {
+                e.fmt(f)
+            }
+        }
+    }
+}
+
+impl IntoResponse for EmailError {
+    fn into_response(self) -> Response {
+        (
+            self.status_code(),
+            format!("Something went wrong: {}", self),
+        )
+            .into_response()
+    }
+}
+
+/// Largest request accepted on inbound email routes.
+///
+/// Mailgun accepts messages up to 25 MB, which are posted along with their parsed fields and
+/// the multipart encoding overhead.
+pub const MAX_INBOUND_REQUEST_SIZE: usize = 40 * 1024 * 1024;
+
+/// Wraps a failure to read a multipart payload: malformed or oversized payloads can never be read,
+/// while other failures (e.g. a dropped connection) may go away on retry.
+fn multipart_error(e: MultipartError, context: String) -> EmailError {
+    let status = e.status();
+    let error = anyhow::Error::new(e).context(context);
+
+    if status.is_client_error() {
+        EmailError::Permanent(error)
+    } else {
+        EmailError::Transient(error)
+    }
+}
+
+/// An email received from any provider, reduced to the parts needed by the newsletter parser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboundEmail {
+    pub from: String,
+    pub subject: String,
+    pub html_body: String,
+    pub attachments: Vec<Attachment>,
+}
+
+impl InboundEmail {
+    /// Images embedded in the HTML body through `cid:` URLs, such as film posters
+    pub fn inline_images(&self) -> impl Iterator<Item = &Attachment> {
+        self.attachments.iter().filter(|a| {
+            a.content_type.starts_with("image/")
+                && a.content_id
+                    .as_ref()
+                    .is_some_and(|id| self.html_body.contains(&format!("cid:{}", id)))
+        })
+    }
+
+    /// PDF attachments, such as program leaflets
+    pub fn documents(&self) -> impl Iterator<Item = &Attachment> {
+        self.attachments
+            .iter()
+            .filter(|a| a.content_type == "application/pdf")
+    }
+}
+
+/// A file attached to an inbound email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub filename: Option<String>,
+    pub content_type: String,
+    /// `Content-ID` of inline attachments, without angle brackets
+    pub content_id: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// A service that delivers inbound emails to the bot via HTTP.
+///
+/// Every enabled provider is routed under `/mail/<name>` and is responsible for authenticating
+/// the request and extracting the email from its own payload format.
+pub trait InboundEmailProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn receive(&self, request: Request) -> BoxFuture<'_, Result<InboundEmail, EmailError>>;
+}
+
+/// Reads the providers listed in `INBOUND_EMAIL_PROVIDERS` (defaults to `mailgun`),
+/// along with the credentials each of them needs.
+pub fn providers_from_env() -> anyhow::Result<Vec<Box<dyn InboundEmailProvider>>> {
+    let enabled_providers =
+        std::env::var("INBOUND_EMAIL_PROVIDERS").unwrap_or_else(|_| "mailgun".to_string());
+
+    enabled_providers
+        .split(",")
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| -> anyhow::Result<Box<dyn InboundEmailProvider>> {
+            match name {
+                "mailgun" => Ok(Box::new(MailgunProvider {
+                    signing_key: std::env::var("MAILGUN_WEBHOOK_SIGNING_KEY").context(
+                        "Unable to get environment variable MAILGUN_WEBHOOK_SIGNING_KEY",
+                    )?,
+                })),
+                "sendgrid" => Ok(Box::new(SendGridProvider {
+                    credentials: BasicCredentials::from_env("SENDGRID_INBOUND")?,
+                })),
+                "postmark" => Ok(Box::new(PostmarkProvider {
+                    credentials: BasicCredentials::from_env("POSTMARK_INBOUND")?,
+                })),
+                "mime" => Ok(Box::new(RawMimeProvider {
+                    upload_token: std::env::var("MIME_UPLOAD_TOKEN")
+                        .context("Unable to get environment variable MIME_UPLOAD_TOKEN")?,
+                })),
+                _ => bail!("Unknown inbound email provider '{}'", name),
+            }
+        })
+        .collect()
+}
+
+/// Parses a raw RFC 822 message, picking its HTML part as body.
+pub fn parse_mime_email(raw_message: &[u8]) -> anyhow::Result<InboundEmail> {
+    let message = MessageParser::default()
+        .parse(raw_message)
+        .ok_or(anyhow!("Unable to parse MIME message"))?;
+
+    let from = message
+        .from()
+        .and_then(|from| from.first())
+        .and_then(|from| from.address())
+        .ok_or(anyhow!("MIME message has no sender address"))?
+        .to_string();
+
+    let subject = message
+        .subject()
+        .ok_or(anyhow!("MIME message has no subject"))?
+        .to_string();
+
+    let html_body = message
+        .body_html(0)
+        .ok_or(anyhow!("MIME message has no HTML body"))?
+        .into_owned();
+
+    let attachments = message
+        .attachments()
+        .map(|part| Attachment {
+            filename: part.attachment_name().map(str::to_string),
+            content_type: part
+                .content_type()
+                .map(|c| format!("{}/{}", c.ctype(), c.subtype().unwrap_or_default()))
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+            content_id: part.content_id().map(normalize_content_id),
+            data: part.contents().to_vec(),
+        })
+        .collect();
+
+    Ok(InboundEmail {
+        from,
+        subject,
+        html_body,
+        attachments,
+    })
+}
+
+fn normalize_content_id(content_id: &str) -> String {
+    content_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+}
+
+struct BasicCredentials {
+    username: String,
+    password: String,
+}
+
+impl BasicCredentials {
+    fn from_env(prefix: &str) -> anyhow::Result<Self> {
+        let username_var = format!("{}_USERNAME", prefix);
+        let password_var = format!("{}_PASSWORD", prefix);
+
+        Ok(BasicCredentials {
+            username: std::env::var(&username_var)
+                .with_context(|| format!("Unable to get environment variable {}", username_var))?,
+            password: std::env::var(&password_var)
+                .with_context(|| format!("Unable to get environment variable {}", password_var))?,
+        })
+    }
+
+    async fn verify(&self, request: Request) -> Result<Request, EmailError> {
+        let (mut parts, body) = request.into_parts();
+        let AuthBasic((username, password)) = AuthBasic::from_request_parts(&mut parts, &())
+            .await
+            .map_err(|(_, e)| EmailError::Unauthorized(anyhow!("{}", e)))?;
+
+        let username_matches = secrets_match(&username, &self.username);
+        let password_matches = password
+            .as_deref()
+            .is_some_and(|password| secrets_match(password, &self.password));
+        if !(username_matches & password_matches) {
+            return Err(EmailError::Unauthorized(anyhow!("Invalid credentials")));
+        }
+
+        Ok(Request::from_parts(parts, body))
+    }
+}
+
+/// Compares a secret in constant time, so that timing does not reveal how much of it was guessed.
+pub fn secrets_match(given: &str, expected: &str) -> bool {
+    given.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MailgunWebhookBody {
+    from: String,
+    subject: String,
+    #[serde(rename = "body-html")]
+    html_body: String,
+    token: String,
+    signature: String,
+    timestamp: u64,
+}
+
+/// How old a Mailgun signature may be, in seconds.
+///
+/// Large enough to accept the retries Mailgun makes over 8 hours, while still rejecting payloads
+/// replayed long after they were captured.
+const MAILGUN_SIGNATURE_MAX_AGE: u64 = 9 * 60 * 60;
+
+fn verify_mailgun_signature(
+    signing_key: &str,
+    token: &str,
+    timestamp: u64,
+    signature: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    if now.abs_diff(timestamp) > MAILGUN_SIGNATURE_MAX_AGE {
+        bail!("Signature timestamp {} is too old", timestamp);
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes())
+        .expect("HMAC can take key of any size");
+    mac.update(format!("{}{}", timestamp, token).as_bytes());
+
+    mac.verify_slice(&hex::decode(signature.as_bytes())?)
+        .context("Unable to verify signature")
+}
+
+/// [Mailgun](https://www.mailgun.com/) inbound routes, signed with the webhook signing key.
+struct MailgunProvider {
+    signing_key: String,
+}
+
+impl InboundEmailProvider for MailgunProvider {
+    fn name(&self) -> &'static str {
+        "mailgun"
+    }
+
+    fn receive(&self, request: Request) -> BoxFuture<'_, Result<InboundEmail, EmailError>> {
+        Box::pin(async move {
+            info!("Received webhook from Mailgun");
+            let is_multipart = request
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("multipart/form-data"));
+
+            let (payload, attachments) = if is_multipart {
+                read_mailgun_multipart(request).await?
+            } else {
+                let Form(payload) = Form::<MailgunWebhookBody>::from_request(request, &())
+                    .await
+                    .map_err(|e| {
+                        EmailError::Permanent(anyhow!("Invalid Mailgun payload: {}", e))
+                    })?;
+
+                (payload, Vec::new())
+            };
+
+            verify_mailgun_signature(
+                &self.signing_key,
+                &payload.token,
+                payload.timestamp,
+                &payload.signature,
+                Utc::now().timestamp() as u64,
+            )
+            .context("Payload signature verification failed")
+            .map_err(EmailError::Unauthorized)?;
+
+            Ok(InboundEmail {
+                from: payload.from,
+                subject: payload.subject,
+                html_body: payload.html_body,
+                attachments,
+            })
+        })
+    }
+}
+
+/// Reads a `multipart/form-data` Mailgun payload, as posted by forwarding routes and by
+/// "store and notify" when the message has attachments or inline images.
+///
+/// Inline attachments are matched to their `Content-ID` through the `content-id-map` field.
+async fn read_mailgun_multipart(
+    request: Request,
+) -> Result<(MailgunWebhookBody, Vec<Attachment>), EmailError> {
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(|e| EmailError::Permanent(anyhow!("Invalid Mailgun payload: {}", e)))?;
+
+    let mut fields = HashMap::new();
+    let mut attachments = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| multipart_error(e, "Unable to read Mailgun payload field".to_string()))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+
+        if field.file_name().is_some() {
+            let filename = field.file_name().map(str::to_string);
+            let content_type = field
+                .content_type()
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            let data = field.bytes().await.map_err(|e| {
+                multipart_error(e, format!("Unable to read Mailgun attachment '{}'", name))
+            })?;
+
+            attachments.push((
+                name,
+                Attachment {
+                    filename,
+                    content_type,
+                    content_id: None,
+                    data: data.to_vec(),
+                },
+            ));
+        } else {
+            let value = field.text().await.map_err(|e| {
+                multipart_error(e, format!("Unable to read Mailgun field '{}'", name))
+            })?;
+
+            fields.insert(name, value);
+        }
+    }
+
+    let content_ids: HashMap<String, String> = match fields.get("content-id-map") {
+        Some(raw) => serde_json::from_str::<HashMap<String, String>>(raw)
+            .context("Invalid Mailgun content id map")
+            .map_err(EmailError::Permanent)?
+            .into_iter()
+            .map(|(content_id, field_name)| (field_name, normalize_content_id(&content_id)))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    let attachments = attachments
+        .into_iter()
+        .map(|(field_name, mut attachment)| {
+            attachment.content_id = content_ids.get(&field_name).cloned();
+            attachment
+        })
+        .collect();
+
+    let mut take_field = |name: &str| {
+        fields
+            .remove(name)
+            .ok_or(EmailError::Permanent(anyhow!("Missing `{}` field", name)))
+    };
+
+    let payload = MailgunWebhookBody {
+        from: take_field("from")?,
+        subject: take_field("subject")?,
+        html_body: take_field("body-html")?,
+        token: take_field("token")?,
+        signature: take_field("signature")?,
+        timestamp: u64::from_str(&take_field("timestamp")?)
+            .context("Invalid Mailgun timestamp")
+            .map_err(EmailError::Permanent)?,
+    };
+
+    Ok((payload, attachments))
+}
+
+/// [SendGrid Inbound Parse](https://www.twilio.com/docs/sendgrid/for-developers/parsing-email/setting-up-the-inbound-parse-webhook),
+/// authenticated with HTTP basic credentials set in the webhook URL.
+///
+/// Both the default payload and the "raw" one (the full MIME message in the `email` field) are supported.
+struct SendGridProvider {
+    credentials: BasicCredentials,
+}
+
+impl InboundEmailProvider for SendGridProvider {
+    fn name(&self) -> &'static str {
+        "sendgrid"
+    }
+
+    fn receive(&self, request: Request) -> BoxFuture<'_, Result<InboundEmail, EmailError>> {
+        Box::pin(async move {
+            info!("Received webhook from SendGrid");
+            let request = self.credentials.verify(request).await?;
+            let mut multipart = Multipart::from_request(request, &())
+                .await
+                .map_err(|e| EmailError::Permanent(anyhow!("Invalid SendGrid payload: {}", e)))?;
+
+            let (mut from, mut subject, mut html_body, mut raw_message) = (None, None, None, None);
+            while let Some(field) = multipart.next_field().await.map_err(|e| {
+                multipart_error(e, "Unable to read SendGrid payload field".to_string())
+            })? {
+                let name = field.name().unwrap_or_default().to_string();
+                let value = field.text().await.map_err(|e| {
+                    multipart_error(e, format!("Unable to read SendGrid field '{}'", name))
+                })?;
+
+                match name.as_str() {
+                    "from" => from = Some(value),
+                    "subject" => subject = Some(value),
+                    "html" => html_body = Some(value),
+                    "email" => raw_message = Some(value),
+                    _ => {}
+                }
+            }
+
+            if let Some(raw_message) = raw_message {
+                return parse_mime_email(raw_message.as_bytes()).map_err(EmailError::Permanent);
+            }
+
+            Ok(InboundEmail {
+                from: from.ok_or(EmailError::Permanent(anyhow!("Missing `from` field")))?,
+                subject: subject
+                    .ok_or(EmailError::Permanent(anyhow!("Missing `subject` field")))?,
+                html_body: html_body
+                    .ok_or(EmailError::Permanent(anyhow!("Missing `html` field")))?,
+                attachments: Vec::new(),
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkInboundBody {
+    from: String,
+    subject: String,
+    html_body: String,
+}
+
+/// [Postmark](https://postmarkapp.com/developer/webhooks/inbound-webhook) inbound JSON webhooks,
+/// authenticated with HTTP basic credentials set in the webhook URL.
+struct PostmarkProvider {
+    credentials: BasicCredentials,
+}
+
+impl InboundEmailProvider for PostmarkProvider {
+    fn name(&self) -> &'static str {
+        "postmark"
+    }
+
+    fn receive(&self, request: Request) -> BoxFuture<'_, Result<InboundEmail, EmailError>> {
+        Box::pin(async move {
+            info!("Received webhook from Postmark");
+            let request = self.credentials.verify(request).await?;
+            let Json(payload) = Json::<PostmarkInboundBody>::from_request(request, &())
+                .await
+                .map_err(|e| EmailError::Permanent(anyhow!("Invalid Postmark payload: {}", e)))?;
+
+            Ok(InboundEmail {
+                from: payload.from,
+                subject: payload.subject,
+                html_body: payload.html_body,
+                attachments: Vec::new(),
+            })
+        })
+    }
+}
+
+/// Raw RFC 822 messages uploaded as request body, authenticated with a bearer token.
+struct RawMimeProvider {
+    upload_token: String,
+}
+
+impl InboundEmailProvider for RawMimeProvider {
+    fn name(&self) -> &'static str {
+        "mime"
+    }
+
+    fn receive(&self, request: Request) -> BoxFuture<'_, Result<InboundEmail, EmailError>> {
+        Box::pin(async move {
+            info!("Received raw MIME upload");
+            let (mut parts, body) = request.into_parts();
+            let AuthBearer(token) = AuthBearer::from_request_parts(&mut parts, &())
+                .await
+                .map_err(|(_, e)| EmailError::Unauthorized(anyhow!("{}", e)))?;
+
+            if !secrets_match(&token, &self.upload_token) {
+                return Err(EmailError::Unauthorized(anyhow!("Invalid token")));
+            }
+
+            let raw_message = Bytes::from_request(Request::from_parts(parts, body), &())
+                .await
+                .map_err(|e| {
+                    let error = anyhow!("Unable to read request body: {}", e.body_text());
+                    if e.status().is_client_error() {
+                        EmailError::Permanent(error)
+                    } else {
+                        EmailError::Transient(error)
+                    }
+                })?;
+
+            parse_mime_email(&raw_message).map_err(EmailError::Permanent)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inbound::{
+        parse_mime_email, read_mailgun_multipart, verify_mailgun_signature, Attachment,
+        BasicCredentials, EmailError, InboundEmail, InboundEmailProvider, PostmarkProvider,
+        SendGridProvider,
+    };
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    const SIGNING_KEY: &str = "signing-key";
+    const TOKEN: &str = "token";
+    const TIMESTAMP: u64 = 1_727_254_800;
+    /// `user:pass`, encoded for HTTP basic authentication
+    const BASIC_AUTHORIZATION: &str = "Basic dXNlcjpwYXNz";
+
+    fn sign(timestamp: u64, token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SIGNING_KEY.as_bytes()).unwrap();
+        mac.update(format!("{}{}", timestamp, token).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn credentials() -> BasicCredentials {
+        BasicCredentials {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        }
+    }
+
+    /// Builds a `multipart/form-data` body out of `(name, filename, content type, content)` parts
+    fn multipart_body(
+        boundary: &str,
+        parts: &[(&str, Option<&str>, Option<&str>, &[u8])],
+    ) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, filename, content_type, content) in parts {
+            body.extend(format!("--{}\r\n", boundary).as_bytes());
+            body.extend(format!("Content-Disposition: form-data; name=\"{}\"", name).as_bytes());
+            if let Some(filename) = filename {
+                body.extend(format!("; filename=\"{}\"", filename).as_bytes());
+            }
+            body.extend(b"\r\n");
+            if let Some(content_type) = content_type {
+                body.extend(format!("Content-Type: {}\r\n", content_type).as_bytes());
+            }
+            body.extend(b"\r\n");
+            body.extend(*content);
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{}--\r\n", boundary).as_bytes());
+
+        body
+    }
+
+    #[test]
+    fn mailgun_signature_is_verified() {
+        let signature = sign(TIMESTAMP, TOKEN);
+
+        assert!(verify_mailgun_signature(
+            SIGNING_KEY,
+            TOKEN,
+            TIMESTAMP,
+            &signature,
+            TIMESTAMP + 60
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn mailgun_signature_of_other_token_is_rejected() {
+        let signature = sign(TIMESTAMP, "other-token");
+
+        assert!(verify_mailgun_signature(
+            SIGNING_KEY,
+            TOKEN,
+            TIMESTAMP,
+            &signature,
+            TIMESTAMP + 60
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn stale_mailgun_signature_is_rejected() {
+        let signature = sign(TIMESTAMP, TOKEN);
+        let a_day_later = TIMESTAMP + 24 * 60 * 60;
+
+        assert!(
+            verify_mailgun_signature(SIGNING_KEY, TOKEN, TIMESTAMP, &signature, a_day_later)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn mailgun_multipart_attachments_are_matched_to_content_ids() {
+        let pdf = b"%PDF-1.4 programma";
+        let poster = [0x89, b'P', b'N', b'G', 0x00, 0xff];
+        let body = multipart_body(
+            "boundary",
+            &[
+                ("from", None, None, b"newsletter@spazioalfieri.it"),
+                ("subject", None, None, b"programmazione"),
+                (
+                    "body-html",
+                    None,
+                    None,
+                    b"<h1>Programmazione</h1><img src=\"cid:ii_poster\">",
+                ),
+                ("token", None, None, TOKEN.as_bytes()),
+                ("signature", None, None, b"signature"),
+                ("timestamp", None, None, TIMESTAMP.to_string().as_bytes()),
+                (
+                    "content-id-map",
+                    None,
+                    None,
+                    br#"{"<ii_poster>": "attachment-2"}"#,
+                ),
+                (
+                    "attachment-1",
+                    Some("programma.pdf"),
+                    Some("application/pdf"),
+                    pdf,
+                ),
+                (
+                    "attachment-2",
+                    Some("poster.png"),
+                    Some("image/png"),
+                    &poster,
+                ),
+            ],
+        );
+        let request = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .unwrap();
+
+        let (payload, attachments) = read_mailgun_multipart(request).await.unwrap();
+
+        assert_eq!(payload.from, "newsletter@spazioalfieri.it");
+        assert_eq!(payload.token, TOKEN);
+        assert_eq!(payload.timestamp, TIMESTAMP);
+        assert_eq!(
+            attachments,
+            vec![
+                Attachment {
+                    filename: Some("programma.pdf".to_string()),
+                    content_type: "application/pdf".to_string(),
+                    content_id: None,
+                    data: pdf.to_vec(),
+                },
+                Attachment {
+                    filename: Some("poster.png".to_string()),
+                    content_type: "image/png".to_string(),
+                    content_id: Some("ii_poster".to_string()),
+                    data: poster.to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_mailgun_multipart_is_rejected_permanently() {
+        // Without `DefaultBodyLimit` the limit is 2 MB
+        let attachment = vec![0; 3 * 1024 * 1024];
+        let body = multipart_body(
+            "boundary",
+            &[(
+                "attachment-1",
+                Some("programma.pdf"),
+                Some("application/pdf"),
+                attachment.as_slice(),
+            )],
+        );
+        let request = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .unwrap();
+
+        let result = read_mailgun_multipart(request).await;
+
+        assert!(matches!(result, Err(EmailError::Permanent(_))));
+    }
+
+    #[tokio::test]
+    async fn sendgrid_payload_is_extracted() {
+        let provider = SendGridProvider {
+            credentials: credentials(),
+        };
+        let body = multipart_body(
+            "boundary",
+            &[
+                (
+                    "from",
+                    None,
+                    None,
+                    b"Spazio Alfieri <newsletter@spazioalfieri.it>",
+                ),
+                ("subject", None, None, b"programmazione"),
+                ("html", None, None, b"<h1>Programmazione</h1>"),
+                ("text", None, None, b"Programmazione"),
+            ],
+        );
+        let request = Request::builder()
+            .method("POST")
+            .header(AUTHORIZATION, BASIC_AUTHORIZATION)
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .unwrap();
+
+        let email = provider.receive(request).await.unwrap();
+
+        assert_eq!(
+            email,
+            InboundEmail {
+                from: "Spazio Alfieri <newsletter@spazioalfieri.it>".to_string(),
+                subject: "programmazione".to_string(),
+                html_body: "<h1>Programmazione</h1>".to_string(),
+                attachments: Vec::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn postmark_payload_is_extracted() {
+        let provider = PostmarkProvider {
+            credentials: credentials(),
+        };
+        let request = Request::builder()
+            .method("POST")
+            .header(AUTHORIZATION, BASIC_AUTHORIZATION)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"From": "newsletter@spazioalfieri.it", "Subject": "programmazione", "HtmlBody": "<h1>Programmazione</h1>", "TextBody": "Programmazione"}"#,
+            ))
+            .unwrap();
+
+        let email = provider.receive(request).await.unwrap();
+
+        assert_eq!(
+            email,
+            InboundEmail {
+                from: "newsletter@spazioalfieri.it".to_string(),
+                subject: "programmazione".to_string(),
+                html_body: "<h1>Programmazione</h1>".to_string(),
+                attachments: Vec::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn postmark_request_with_wrong_credentials_is_rejected() {
+        let provider = PostmarkProvider {
+            credentials: credentials(),
+        };
+        let request = Request::builder()
+            .method("POST")
+            // `user:wrong`
+            .header(AUTHORIZATION, "Basic dXNlcjp3cm9uZw==")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+
+        let result = provider.receive(request).await;
+
+        assert!(matches!(result, Err(EmailError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn mime_message_html_part_is_extracted() {
+        let raw_message = "\
+From: Spazio Alfieri <newsletter@spazioalfieri.it>\r
+To: bot@example.com\r
+Subject: Spazio Alfieri =?utf-8?q?=E2=80=A2?= programmazione 25 settembre > 2 ottobre\r
+MIME-Version: 1.0\r
+Content-Type: multipart/alternative; boundary=\"boundary\"\r
+\r
+--boundary\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+Programmazione\r
+--boundary\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<html><body><h1>Programmazione</h1></body></html>\r
+--boundary--\r
+";
+
+        let email = parse_mime_email(raw_message.as_bytes()).unwrap();
+
+        assert_eq!(
+            email,
+            InboundEmail {
+                from: "newsletter@spazioalfieri.it".to_string(),
+                subject: "Spazio Alfieri • programmazione 25 settembre > 2 ottobre".to_string(),
+                html_body: "<html><body><h1>Programmazione</h1></body></html>".to_string(),
+                attachments: Vec::new(),
+            }
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use chrono::Datelike;
+use serde::Serialize;
+
+/// Language of the messages rendered from templates
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Language {
+    #[default]
+    Italian,
+    English,
+}
+
+/// Fixed strings of the message templates, in a given language
+#[derive(Serialize)]
+pub struct Strings {
+    pub new_films: &'static str,
+    pub next_dates: &'static str,
+    pub open_in_browser: &'static str,
+    pub past_screening: &'static str,
+    pub past_screenings: &'static str,
+    pub finished: &'static str,
+    pub tonight: &'static str,
+}
+
+const ITALIAN_STRINGS: Strings = Strings {
+    new_films: "Nuovi film in arrivo allo Spazio Alfieri!",
+    next_dates: "Prossime date:",
+    open_in_browser: "Apri nel browser",
+    past_screening: "proiezione passata",
+    past_screenings: "proiezioni passate",
+    finished: "Terminati",
+    tonight: "Oggi allo Spazio Alfieri",
+};
+
+const ENGLISH_STRINGS: Strings = Strings {
+    new_films: "New films coming to Spazio Alfieri!",
+    next_dates: "Next screenings:",
+    open_in_browser: "Open in browser",
+    past_screening: "past screening",
+    past_screenings: "past screenings",
+    finished: "No longer showing",
+    tonight: "Today at Spazio Alfieri",
+};
+
+impl Language {
+    fn weekdays(&self) -> [&'static str; 7] {
+        match self {
+            Language::Italian => [
+                "lunedì",
+                "martedì",
+                "mercoledì",
+                "giovedì",
+                "venerdì",
+                "sabato",
+                "domenica",
+            ],
+            Language::English => [
+                "Monday",
+                "Tuesday",
+                "Wednesday",
+                "Thursday",
+                "Friday",
+                "Saturday",
+                "Sunday",
+            ],
+        }
+    }
+
+    fn months(&self) -> [&'static str; 12] {
+        match self {
+            Language::Italian => [
+                "gennaio",
+                "febbraio",
+                "marzo",
+                "aprile",
+                "maggio",
+                "giugno",
+                "luglio",
+                "agosto",
+                "settembre",
+                "ottobre",
+                "novembre",
+                "dicembre",
+            ],
+            Language::English => [
+                "January",
+                "February",
+                "March",
+                "April",
+                "May",
+                "June",
+                "July",
+                "August",
+                "September",
+                "October",
+                "November",
+                "December",
+            ],
+        }
+    }
+
+    pub fn strings(&self) -> &'static Strings {
+        match self {
+            Language::Italian => &ITALIAN_STRINGS,
+            Language::English => &ENGLISH_STRINGS,
+        }
+    }
+
+    /// Full name of the weekday of `date`, e.g. `mercoledì`
+    pub fn weekday(&self, date: &impl Datelike) -> &'static str {
+        self.weekdays()[date.weekday().num_days_from_monday() as usize]
+    }
+
+    /// Day and full name of the month of `date`, e.g. `25 settembre`
+    pub fn day_and_month(&self, date: &impl Datelike) -> String {
+        format!("{} {}", date.day(), self.months()[date.month0() as usize])
+    }
+
+    /// Date with abbreviated weekday and month names, e.g. `mer 25 set`
+    pub fn short_date(&self, date: &impl Datelike) -> String {
+        let abbreviate = |name: &str| name.chars().take(3).collect::<String>();
+
+        format!(
+            "{} {} {}",
+            abbreviate(self.weekday(date)),
+            date.day(),
+            abbreviate(self.months()[date.month0() as usize])
+        )
+    }
+}
+
+impl FromStr for Language {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "it" => Ok(Language::Italian),
+            "en" => Ok(Language::English),
+            _ => bail!("Unknown language '{}', expected 'it' or 'en'", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::locale::Language;
+
+    #[test]
+    fn dates_are_localised() {
+        let date = NaiveDate::from_ymd_opt(2024, 9, 25).unwrap();
+
+        assert_eq!(Language::Italian.short_date(&date), "mer 25 set");
+        assert_eq!(Language::English.short_date(&date), "Wed 25 Sep");
+        assert_eq!(Language::Italian.weekday(&date), "mercoledì");
+        assert_eq!(Language::Italian.day_and_month(&date), "25 settembre");
+        assert_eq!(Language::English.day_and_month(&date), "25 September");
+    }
+}
@@ -3,41 +3,67 @@
 use crate::crontap::types::{AddSchedule, KeyValue, Timezone};
 use crate::crontap::Client;
 use anyhow::{anyhow, bail, Context};
-use axum::extract::State;
+use axum::extract::{DefaultBodyLimit, Request, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{Form, Router};
+use axum::Router;
 use axum_auth::AuthBearer;
-use chrono::{Datelike, Timelike, Utc};
-use chrono_tz::Europe;
-use hmac::{Hmac, Mac};
+use chrono::{DateTime, Datelike, NaiveTime, Timelike, Utc};
+use chrono_tz::{Europe, Tz};
 use itertools::Itertools;
 use migration::{Migrator, MigratorTrait};
 use reqwest::Url;
 use sea_orm::{
-    ActiveModelTrait, ActiveValue, Database, DatabaseConnection, EntityTrait, LoaderTrait,
-    ModelTrait, QueryOrder, TransactionTrait,
+    ActiveModelTrait, ActiveValue, ColumnTrait, Database, DatabaseConnection, EntityTrait,
+    LoaderTrait, ModelTrait, QueryFilter, QueryOrder, TransactionTrait,
 };
-use serde::Deserialize;
-use sha2::Sha256;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use teloxide::prelude::*;
-use teloxide::types::{MessageId, ParseMode, Recipient};
-use teloxide::utils::markdown;
+use teloxide::requests::Request as _;
+use teloxide::types::{
+    BotCommandScope, InputFile, InputMedia, InputMediaPhoto, MessageId, Recipient, ReplyParameters,
+};
+use teloxide::utils::command::BotCommands;
 use tokio::task::JoinSet;
 use tracing::level_filters::LevelFilter;
 use tracing::{error, info};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Layer};
+use crate::commands::Command;
+use crate::forwarded::unwrap_forwarded_email;
+use crate::inbound::{
+    parse_mime_email, Attachment, EmailError, InboundEmail, InboundEmailProvider,
+};
 use crate::parser::{parse_email_body, DateEntry, NewsletterEntry, ProgrammingEntry};
+use crate::telegram::UpdateSender;
 
+mod admin;
+mod approval;
+mod commands;
+mod corrections;
 mod crontap;
+mod formatting;
+mod forwarded;
+mod imap;
+mod inbound;
+mod locale;
+mod messages;
 mod parser;
+mod preferences;
+mod reminders;
+mod search;
+mod subscriptions;
+mod targets;
+mod telegram;
+mod templates;
+mod tonight;
+mod watchlist;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -79,14 +105,66 @@ async fn main() -> anyhow::Result<()> {
         )
     };
 
-    let allowed_senders = std::env::var("ALLOWED_SENDERS")
+    let allowed_senders: HashSet<String> = std::env::var("ALLOWED_SENDERS")
         .context("Unable to get environment variable ALLOWED_SENDERS")?
         .split(",")
         .map(str::to_string)
         .collect();
+    // An empty sender would match any address, both here and in IMAP searches
+    if allowed_senders.iter().any(|s| s.trim().is_empty()) {
+        bail!("ALLOWED_SENDERS must not contain empty addresses");
+    }
+
+    let allowed_forwarders = std::env::var("ALLOWED_FORWARDERS")
+        .map(|raw| {
+            raw.split(",")
+                .map(str::to_string)
+                .filter(|f| !f.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let telegram_webhook_secret = std::env::var("TELEGRAM_WEBHOOK_SECRET")
+        .context("Unable to read TELEGRAM_WEBHOOK_SECRET environment variable")?;
+
+    let reminder_lead_time = match std::env::var("REMINDER_LEAD_MINUTES") {
+        Ok(raw) => chrono::Duration::minutes(
+            i64::from_str(&raw)
+                .with_context(|| format!("Unable to parse reminder lead time '{}' into i64", raw))?,
+        ),
+        Err(_) => chrono::Duration::hours(1),
+    };
+
+    let approval_timeout = std::env::var("APPROVAL_TIMEOUT_MINUTES")
+        .ok()
+        .map(|raw| {
+            i64::from_str(&raw)
+                .map(chrono::Duration::minutes)
+                .with_context(|| format!("Unable to parse approval timeout '{}' into i64", raw))
+        })
+        .transpose()?;
+    let approval_required = match std::env::var("APPROVAL_REQUIRED") {
+        Ok(raw) => bool::from_str(&raw)
+            .with_context(|| format!("Unable to parse APPROVAL_REQUIRED '{}' into bool", raw))?,
+        Err(_) => false,
+    };
+    // a timeout alone enables approval, as it did before `APPROVAL_REQUIRED` was introduced
+    let approval = (approval_required || approval_timeout.is_some()).then_some(
+        approval::ApprovalSettings {
+            timeout: approval_timeout,
+        },
+    );
+
+    let tonight_post_time = std::env::var("TONIGHT_POST_TIME")
+        .ok()
+        .map(|raw| {
+            NaiveTime::parse_from_str(&raw, "%H:%M")
+                .with_context(|| format!("Unable to parse tonight post time '{}' as HH:MM", raw))
+        })
+        .transpose()?;
 
-    let mailgun_webhook_signing_key = std::env::var("MAILGUN_WEBHOOK_SIGNING_KEY")
-        .context("Unable to get environment variable MAILGUN_WEBHOOK_SIGNING_KEY")?;
+    let inbound_email_providers =
+        inbound::providers_from_env().context("Unable to set up inbound email providers")?;
 
     let update_token = std::env::var("UPDATE_TOKEN")
         .context("Unable to read UPDATE_TOKEN environment variable")?;
@@ -109,6 +187,12 @@ async fn main() -> anyhow::Result<()> {
     let webhook_update_url = host_baseurl
         .join("/update")
         .context("Unable to join update path to host baseurl")?;
+    let webhook_tonight_url = host_baseurl
+        .join("/tonight")
+        .context("Unable to join tonight path to host baseurl")?;
+    let telegram_webhook_url = host_baseurl
+        .join("/telegram")
+        .context("Unable to join telegram path to host baseurl")?;
 
     let db_host = std::env::var("POSTGRES_HOST")
         .context("Unable to read POSTGRES_HOST environment variable")?;
@@ -126,25 +210,158 @@ async fn main() -> anyhow::Result<()> {
     .await?;
     Migrator::up(&db_connection, None).await?;
 
+    let mut main_target = targets::Target::new(targets::MAIN_TARGET, channel_id);
+    main_target.layout = std::env::var("CHANNEL_LAYOUT")
+        .ok()
+        .map(|raw| templates::Layout::from_str(&raw))
+        .transpose()
+        .context("Unable to parse CHANNEL_LAYOUT environment variable")?
+        .unwrap_or_default();
+    main_target.language = std::env::var("CHANNEL_LANGUAGE")
+        .ok()
+        .map(|raw| locale::Language::from_str(&raw))
+        .transpose()
+        .context("Unable to parse CHANNEL_LANGUAGE environment variable")?
+        .unwrap_or_default();
+    main_target.update_options = std::env::var("UPDATE_OPTIONS")
+        .ok()
+        .map(|raw| templates::UpdateOptions::from_str(&raw))
+        .transpose()
+        .context("Unable to parse UPDATE_OPTIONS environment variable")?
+        .unwrap_or_default();
+    let mut publication_targets = vec![main_target];
+    if let Ok(raw) = std::env::var("PUBLICATION_TARGETS") {
+        publication_targets.extend(
+            targets::parse_targets(&raw)
+                .context("Unable to parse PUBLICATION_TARGETS environment variable")?,
+        );
+    }
+
+    let templates_directory = std::env::var("TEMPLATES_DIR").ok().map(PathBuf::from);
+    let templates = templates::Templates::load(templates_directory.as_deref(), &db_connection)
+        .await
+        .context("Unable to load templates")?;
+
+    let (telegram_updates, update_listener) = telegram::webhook_listener();
+
     let server_state = Arc::new(ServerState {
         bot,
-        channel_id,
-        mailgun_webhook_signing_key,
         error_chat_id,
         allowed_senders,
+        allowed_forwarders,
         db_connection,
         update_token,
         crontap_client,
         crontap_client_id,
         crontap_api_key,
         webhook_update_url,
+        webhook_tonight_url,
+        telegram_webhook_secret,
+        telegram_updates,
+        reminder_lead_time,
+        approval,
+        pending_attachments: Default::default(),
+        publish_lock: Default::default(),
+        templates: RwLock::new(templates),
+        templates_directory,
+        targets: publication_targets,
+        tonight_post_time,
+    });
+
+    server_state
+        .bot
+        .set_webhook(telegram_webhook_url)
+        .secret_token(server_state.telegram_webhook_secret.clone())
+        .await
+        .context("Unable to set Telegram webhook")?;
+    server_state
+        .bot
+        .set_my_commands(Command::bot_commands())
+        .scope(BotCommandScope::AllPrivateChats)
+        .await
+        .context("Unable to set bot commands")?;
+    server_state
+        .bot
+        .set_my_commands(commands::group_bot_commands())
+        .scope(BotCommandScope::AllGroupChats)
+        .await
+        .context("Unable to set group bot commands")?;
+    server_state
+        .bot
+        .set_my_commands(admin::AdminCommand::bot_commands())
+        .scope(BotCommandScope::Chat {
+            chat_id: Recipient::Id(server_state.error_chat_id),
+        })
+        .await
+        .context("Unable to set admin bot commands")?;
+
+    let state = server_state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tonight::update_tonight_schedule(&state).await {
+            report_error(&state, "Got error while scheduling tonight post", e).await;
+        }
+    });
+
+    tokio::spawn(reminders::run_scheduler(server_state.clone()));
+    tokio::spawn(subscriptions::run_scheduler(server_state.clone()));
+    if server_state.approval.is_some() {
+        tokio::spawn(approval::run_scheduler(server_state.clone()));
+    }
+
+    let mut dispatcher = Dispatcher::builder(server_state.bot.clone(), commands::schema())
+        .dependencies(dptree::deps![
+            server_state.clone(),
+            corrections::CorrectionStorage::new()
+        ])
+        .build();
+    tokio::spawn(async move {
+        dispatcher
+            .dispatch_with_listener(
+                update_listener,
+                LoggingErrorHandler::with_custom_text("Error from the update listener"),
+            )
+            .await
     });
 
-    let router = Router::new()
+    let mut router = Router::new()
         .route("/health", get(health))
-        .route("/mail", post(receive_newsletter_email))
         .route("/update", post(update_latest_newsletter_message))
-        .with_state(server_state);
+        .route("/tonight", post(tonight::post_tonight_message))
+        .route("/telegram", post(telegram::receive_update));
+
+    for provider in inbound_email_providers {
+        let provider: Arc<dyn InboundEmailProvider> = provider.into();
+        let provider_name = provider.name();
+        let handler = post(
+            |State(state): State<Arc<ServerState>>, request: Request| async move {
+                receive_newsletter_email(state, provider, request).await
+            },
+        )
+        .layer(DefaultBodyLimit::max(inbound::MAX_INBOUND_REQUEST_SIZE));
+
+        // `/mail` was the only inbound route before multiple providers were supported
+        if provider_name == "mailgun" {
+            router = router.route("/mail", handler.clone());
+        }
+        router = router.route(&format!("/mail/{}", provider_name), handler);
+    }
+
+    if let Some(imap_config) =
+        imap::ImapConfig::from_env().context("Unable to read IMAP configuration")?
+    {
+        let state = server_state.clone();
+        tokio::spawn(imap::poll_mailbox(
+            imap_config,
+            server_state
+                .allowed_senders
+                .union(&server_state.allowed_forwarders)
+                .cloned()
+                .collect(),
+            move |raw_message| Box::pin(receive_imap_email(state.clone(), raw_message)),
+        ));
+    }
+
+    let router = router.with_state(server_state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
         .await
@@ -160,16 +377,39 @@ async fn health() -> &'static str {
 
 struct ServerState {
     bot: Bot,
-    channel_id: ChatId,
-    mailgun_webhook_signing_key: String,
     error_chat_id: ChatId,
     allowed_senders: HashSet<String>,
+    /// Addresses allowed to manually forward newsletters, which are unwrapped before parsing
+    allowed_forwarders: HashSet<String>,
     db_connection: DatabaseConnection,
     update_token: String,
     crontap_client: Client,
     crontap_client_id: String,
     crontap_api_key: String,
     webhook_update_url: Url,
+    webhook_tonight_url: Url,
+    telegram_webhook_secret: String,
+    telegram_updates: UpdateSender,
+    /// How long before a screening its reminders are sent
+    reminder_lead_time: chrono::Duration,
+    /// If set, newsletters are previewed in the error chat and published after approval
+    approval: Option<approval::ApprovalSettings>,
+    pending_attachments: approval::PendingAttachments,
+    publish_lock: approval::PublishLock,
+    /// Layout of the channel posts and of the films in bot replies
+    templates: RwLock<templates::Templates>,
+    templates_directory: Option<PathBuf>,
+    /// Chats where newsletters are published, starting with the main target for `CHANNEL_ID`
+    targets: Vec<targets::Target>,
+    /// Time of the daily post with today's screenings, if enabled
+    tonight_post_time: Option<NaiveTime>,
+}
+
+impl ServerState {
+    /// Target of `CHANNEL_ID`, where attachments, pins and daily posts are sent
+    fn main_target(&self) -> &targets::Target {
+        &self.targets[0]
+    }
 }
 
 #[derive(Debug)]
@@ -200,104 +440,320 @@ where
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-struct MailgunWebhookBody {
-    from: String,
-    subject: String,
-    #[serde(rename = "body-html")]
-    html_body: String,
-    token: String,
-    signature: String,
-    timestamp: u64,
-}
-
-fn verify_mailgun_signature(
-    signing_key: &str,
-    token: &str,
-    timestamp: u64,
-    signature: &str,
-) -> anyhow::Result<()> {
-    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes())
-        .expect("HMAC can take key of any size");
-    mac.update(format!("{}{}", timestamp, token).as_bytes());
-
-    mac.verify_slice(&hex::decode(signature.as_bytes())?)
-        .context("Unable to verify signature")
-}
-
 async fn receive_newsletter_email(
-    State(state): State<Arc<ServerState>>,
-    payload: Form<MailgunWebhookBody>,
-) -> Result<(), ServerError> {
+    state: Arc<ServerState>,
+    provider: Arc<dyn InboundEmailProvider>,
+    request: Request,
+) -> Result<(), EmailError> {
     async fn handle_email(
         state: Arc<ServerState>,
-        Form(payload): Form<MailgunWebhookBody>,
-    ) -> Result<(), ServerError> {
-        info!("Received webhook from Mailgun");
-        verify_mailgun_signature(
-            &state.mailgun_webhook_signing_key,
-            &payload.token,
-            payload.timestamp,
-            &payload.signature,
-        )
-        .context("Payload signature verification failed")?;
+        provider: Arc<dyn InboundEmailProvider>,
+        request: Request,
+    ) -> Result<(), EmailError> {
+        let email = provider.receive(request).await?;
+        process_inbound_email(state, email).await
+    }
 
-        if !state
-            .allowed_senders
-            .iter()
-            .any(|s| payload.from.contains(s))
-        {
-            return Err(ServerError(anyhow!(
-                "Got mail from unknown sender: {}",
-                &payload.from
-            )));
+    if let Err(e) = handle_email(state.clone(), provider, request).await {
+        error!("{:#}", e);
+
+        // Failing to report the error must not change the status code returned to the provider
+        let result = state
+            .bot
+            .send_message(
+                state.error_chat_id,
+                format!("Got error while handling email: {:#}", e),
+            )
+            .await;
+        if let Err(send_error) = result {
+            error!("Unable to send error message: {:#}", send_error);
         }
 
-        let newsletter_entry =
-            parse_email_body(payload.subject, payload.html_body)
-                .context("Could not parse email body")?;
+        return Err(e);
+    }
+
+    Ok(())
+}
 
-        let mut saved_newsletter =
-            persist_newsletter_entry(&newsletter_entry, &state.db_connection)
-                .await
-                .context("Unable to persist newsletter entry")?;
+async fn receive_imap_email(
+    state: Arc<ServerState>,
+    raw_message: Vec<u8>,
+) -> Result<(), EmailError> {
+    info!("Received email from IMAP mailbox");
+    let result = match parse_mime_email(&raw_message) {
+        Ok(email) => process_inbound_email(state.clone(), email).await,
+        Err(e) => Err(EmailError::Permanent(
+            e.context("Unable to parse MIME message"),
+        )),
+    };
+
+    if let Err(e) = &result {
+        error!("{:#}", e);
 
-        let message_text = make_message(&newsletter_entry);
-        let message = state
+        // Failing to report the error must not turn a permanent failure into a retried one
+        let send_result = state
             .bot
-            .send_message(Recipient::Id(state.channel_id), message_text)
-            .parse_mode(ParseMode::MarkdownV2)
-            .await
-            .context("Unable to send update message")?;
+            .send_message(
+                state.error_chat_id,
+                format!("Got error while handling email: {:#}", e),
+            )
+            .await;
+        if let Err(send_error) = send_result {
+            error!("Unable to send error message: {:#}", send_error);
+        }
+    }
+
+    result
+}
+
+/// Runs an inbound email through the whole pipeline: sender check, parsing, persistence,
+/// publication to the channel and schedule update.
+async fn process_inbound_email(
+    state: Arc<ServerState>,
+    email: InboundEmail,
+) -> Result<(), EmailError> {
+    let email = if state
+        .allowed_forwarders
+        .iter()
+        .any(|f| email.from.contains(f))
+    {
+        match unwrap_forwarded_email(&email) {
+            Some(original_email) => {
+                info!("Unwrapped email forwarded by {}", &email.from);
+                original_email
+            }
+            None => email,
+        }
+    } else {
+        email
+    };
+
+    if !state.allowed_senders.iter().any(|s| email.from.contains(s)) {
+        return Err(EmailError::Unauthorized(anyhow!(
+            "Got mail from unknown sender: {}",
+            &email.from
+        )));
+    }
+
+    let inline_images = email.inline_images().cloned().collect_vec();
+    let documents = email.documents().cloned().collect_vec();
+
+    let newsletter_entry = parse_email_body(email.subject, email.html_body)
+        .context("Could not parse email body")
+        .map_err(EmailError::Permanent)?;
+
+    let already_published = find_published_newsletter(&newsletter_entry, &state.db_connection)
+        .await
+        .context("Unable to look up existing newsletter")
+        .map_err(EmailError::Transient)?;
 
-        saved_newsletter.message_id = ActiveValue::Set(Some(message.id.0));
-        saved_newsletter
-            .save(&state.db_connection)
+    if already_published {
+        info!("Newsletter was already published, skipping to schedule update");
+    } else {
+        let saved_newsletter = persist_newsletter_entry(&newsletter_entry, &state.db_connection)
             .await
-            .context("Unable to update newsletter with message id")?;
+            .context("Unable to persist newsletter entry")
+            .map_err(EmailError::Transient)?;
 
-        update_schedules(state, newsletter_entry)
+        let attachments = approval::NewsletterAttachments {
+            inline_images,
+            documents,
+        };
+        match &state.approval {
+            Some(approval) => approval::request_approval(
+                &state,
+                saved_newsletter,
+                &newsletter_entry,
+                attachments,
+                approval.timeout,
+            )
             .await
-            .context("Unable to update schedules")?;
+            .context("Unable to request newsletter approval")
+            .map_err(EmailError::Transient)?,
+            None => {
+                publish_newsletter(&state, saved_newsletter, &newsletter_entry, &attachments)
+                    .await
+                    .map_err(EmailError::Transient)?;
+            }
+        }
+    }
 
-        Ok(())
+    update_schedules(state, newsletter_entry)
+        .await
+        .context("Unable to update schedules")
+        .map_err(EmailError::Transient)?;
+
+    Ok(())
+}
+
+/// Posts a saved newsletter to the channel and the other targets, then forwards its attachments
+/// and alerts the users following its films.
+async fn publish_newsletter(
+    state: &ServerState,
+    mut saved_newsletter: entity::newsletter::ActiveModel,
+    newsletter_entry: &NewsletterEntry,
+    attachments: &approval::NewsletterAttachments,
+) -> anyhow::Result<MessageId> {
+    let newsletter_id = saved_newsletter.id.clone().unwrap();
+    let main_target = state.main_target();
+    let message_ids = messages::send_newsletter_messages(
+        state,
+        main_target,
+        newsletter_id,
+        &make_messages(state, main_target, newsletter_entry)?,
+    )
+    .await?;
+    let first_message_id = message_ids[0];
+
+    // the newsletter is already in the channel, so a failure on other targets is only reported
+    for target in &state.targets[1..] {
+        let result = async {
+            let texts = make_messages(state, target, newsletter_entry)?;
+            messages::send_newsletter_messages(state, target, newsletter_id, &texts).await
+        }
+        .await;
+        if let Err(e) = result {
+            let description = format!("Got error while publishing newsletter to {}", target.name);
+            report_error(state, &description, e).await;
+        }
     }
 
-    if let Err(e) = handle_email(state.clone(), payload).await {
-        error!("{:#}", e.0);
+    saved_newsletter.message_id = ActiveValue::Set(Some(first_message_id.0));
+    saved_newsletter
+        .save(&state.db_connection)
+        .await
+        .context("Unable to update newsletter with message id")?;
 
-        let bot = &state.bot;
-        bot.send_message(
-            state.error_chat_id,
-            format!("Got error while handling email: {:#}", e.0),
-        )
+    if let Err(e) = messages::pin_latest_newsletter(state).await {
+        report_error(state, "Got error while pinning newsletter", e).await;
+    }
+
+    // the newsletter is already published at this point, so a retry wouldn't forward them again
+    let result = forward_attachments(
+        state,
+        &attachments.inline_images,
+        &attachments.documents,
+        first_message_id,
+    )
+    .await;
+    if let Err(e) = result {
+        report_error(state, "Got error while forwarding email attachments", e).await;
+    }
+
+    if let Err(e) = watchlist::notify_watchers(state, newsletter_entry).await {
+        report_error(state, "Got error while notifying watchlists", e).await;
+    }
+
+    Ok(first_message_id)
+}
+
+/// Logs an error that doesn't stop the current operation, and reports it to the error chat
+async fn report_error(state: &ServerState, description: &str, e: anyhow::Error) {
+    error!("{}: {:#}", description, e);
+
+    let result = state
+        .bot
+        .send_message(state.error_chat_id, format!("{}: {:#}", description, e))
+        .await;
+    if let Err(e) = result {
+        error!("Unable to send error message: {:#}", e);
+    }
+}
+
+/// Forwards inline images (e.g. posters) and PDF documents (e.g. program leaflets) of a newsletter
+/// email to the channel, as replies to the first newsletter message.
+async fn forward_attachments(
+    state: &ServerState,
+    inline_images: &[Attachment],
+    documents: &[Attachment],
+    message_id: MessageId,
+) -> anyhow::Result<()> {
+    let channel_id = state.main_target().chat_id;
+    fn input_file(attachment: &Attachment) -> InputFile {
+        let file = InputFile::memory(attachment.data.clone());
+        match &attachment.filename {
+            Some(filename) => file.file_name(filename.clone()),
+            None => file,
+        }
+    }
+
+    // albums must contain between 2 and 10 items
+    for images in inline_images.chunks(10) {
+        if let [image] = images {
+            messages::with_retries(|| {
+                state
+                    .bot
+                    .send_photo(channel_id, input_file(image))
+                    .reply_parameters(ReplyParameters::new(message_id))
+                    .send()
+            })
+            .await
+            .context("Unable to send inline image")?;
+        } else {
+            messages::with_retries(|| {
+                let media = images
+                    .iter()
+                    .map(|i| InputMedia::Photo(InputMediaPhoto::new(input_file(i))))
+                    .collect_vec();
+
+                state
+                    .bot
+                    .send_media_group(channel_id, media)
+                    .reply_parameters(ReplyParameters::new(message_id))
+                    .send()
+            })
+            .await
+            .context("Unable to send inline images")?;
+        }
+    }
+
+    for document in documents {
+        messages::with_retries(|| {
+            state
+                .bot
+                .send_document(channel_id, input_file(document))
+                .reply_parameters(ReplyParameters::new(message_id))
+                .send()
+        })
         .await
-        .context("Unable to send error message")?;
+        .context("Unable to send document")?;
     }
 
     Ok(())
 }
 
+/// Looks up a newsletter with the same link as `newsletter_entry`.
+///
+/// Returns `true` if it has already been published to the channel, or is awaiting approval.
+/// A newsletter that was saved but never published (e.g. because sending the message failed)
+/// is deleted, so that a retried delivery can persist it again from scratch.
+async fn find_published_newsletter(
+    newsletter_entry: &NewsletterEntry,
+    db_connection: &DatabaseConnection,
+) -> anyhow::Result<bool> {
+    let existing_newsletter = entity::newsletter::Entity::find()
+        .filter(entity::newsletter::Column::Link.eq(&newsletter_entry.newsletter_link))
+        .one(db_connection)
+        .await
+        .context("Could not fetch newsletter by link")?;
+
+    match existing_newsletter {
+        Some(newsletter)
+            if newsletter.message_id.is_some() || newsletter.preview_message_id.is_some() =>
+        {
+            Ok(true)
+        }
+        Some(newsletter) => {
+            newsletter
+                .delete(db_connection)
+                .await
+                .context("Unable to delete unpublished newsletter")?;
+            Ok(false)
+        }
+        None => Ok(false),
+    }
+}
+
 async fn update_latest_newsletter_message(
     State(state): State<Arc<ServerState>>,
     AuthBearer(token): AuthBearer,
@@ -307,80 +763,159 @@ async fn update_latest_newsletter_message(
             bail!("Invalid token");
         }
 
-        let (newsletter, message_id) = fetch_latest_newsletter(&state.db_connection)
+        update_latest_newsletter(state).await
+    }
+
+    if let Err(e) = do_update(state.clone(), token).await {
+        error!("{:#}", e);
+
+        state
+            .bot
+            .send_message(
+                state.error_chat_id,
+                format!("Got error while updating newsletter message: {:#}", e),
+            )
             .await
-            .context("Unable to get latest newsletter from db")?;
+            .context("Unable to send error message")?;
+    }
+
+    Ok(())
+}
 
-        let updated_text = make_message(&newsletter);
+/// Re-renders the message of the latest newsletter and schedules the next update
+async fn update_latest_newsletter(state: Arc<ServerState>) -> anyhow::Result<()> {
+    let (newsletter, saved_newsletter) = fetch_latest_newsletter(&state.db_connection)
+        .await
+        .context("Unable to get latest newsletter from db")?;
 
-        let mut joinset: JoinSet<anyhow::Result<()>> = JoinSet::new();
+    let mut joinset: JoinSet<anyhow::Result<()>> = JoinSet::new();
+    for (index, target) in state.targets.iter().enumerate() {
+        let updated_texts = make_messages(&state, target, &newsletter)?;
         let _state = state.clone();
         joinset.spawn(async move {
             let state = _state;
-            let updated_text = updated_text;
-            state
-                .bot
-                .edit_message_text(state.channel_id, message_id, updated_text)
-                .parse_mode(ParseMode::MarkdownV2)
+            let target = &state.targets[index];
+            messages::edit_newsletter_messages(&state, target, saved_newsletter.id, &updated_texts)
                 .await
-                .context("Unable to update message")?;
-
-            Ok(())
+                .with_context(|| format!("Unable to update messages in {}", target.name))
         });
+    }
 
-        let _state = state.clone();
-        joinset.spawn(async move {
-            let state = _state;
-            let newsletter = newsletter;
-            update_schedules(state.clone(), newsletter)
-                .await
-                .context("Unable to update schedules")?;
+    let _state = state.clone();
+    joinset.spawn(async move {
+        let state = _state;
+        let newsletter = newsletter;
+        update_schedules(state.clone(), newsletter)
+            .await
+            .context("Unable to update schedules")?;
 
-            Ok(())
-        });
+        Ok(())
+    });
 
-        let results = joinset.join_all().await;
+    let mut results = joinset.join_all().await;
 
-        if results.iter().any(|r| r.is_err()) {
-            let error_string = results
-                .into_iter()
-                .filter_map(|r| r.err())
-                .map(|e| format!("{:#}", e))
-                .join("\n");
+    // the edits may repost the first message of the newsletter, so it can only be pinned afterwards
+    results.push(messages::pin_latest_newsletter(&state).await);
 
-            bail!("{}", error_string);
-        }
+    if results.iter().any(|r| r.is_err()) {
+        let error_string = results
+            .into_iter()
+            .filter_map(|r| r.err())
+            .map(|e| format!("{:#}", e))
+            .join("\n");
 
-        Ok(())
+        bail!("{}", error_string);
     }
 
-    if let Err(e) = do_update(state.clone(), token).await {
-        error!("{:#}", e);
+    Ok(())
+}
 
-        state
-            .bot
-            .send_message(
-                state.error_chat_id,
-                format!("Got error while updating newsletter message: {:#}", e),
-            )
-            .await
-            .context("Unable to send error message")?;
+/// Fetches the screenings between `from` (inclusive) and `to` (exclusive) across all newsletters,
+/// grouped by title and sorted by their first screening.
+///
+/// Newsletters often repeat the films of the previous week, so a screening listed by several
+/// newsletters is only returned once, as listed by the most recent one.
+async fn fetch_programming_entries_between(
+    db_connection: &DatabaseConnection,
+    from: DateTime<Tz>,
+    to: DateTime<Tz>,
+) -> anyhow::Result<Vec<ProgrammingEntry>> {
+    let entries = entity::entry::Entity::find()
+        .filter(entity::entry::Column::Date.gte(from.fixed_offset()))
+        .filter(entity::entry::Column::Date.lt(to.fixed_offset()))
+        .order_by_asc(entity::entry::Column::Date)
+        // entries of later newsletters come first among the ones at the same time
+        .order_by_desc(entity::entry::Column::Id)
+        .find_also_related(entity::program::Entity)
+        .all(db_connection)
+        .await
+        .context("Could not fetch entries from db")?;
+
+    let mut programming_entries: Vec<ProgrammingEntry> = Vec::new();
+    for (entry, program) in entries {
+        let program = program.ok_or(anyhow!("Entry {} has no program", entry.id))?;
+        let date_entry = DateEntry {
+            date: entry.date.with_timezone(&Europe::Rome),
+            additional_details: entry.details,
+        };
+
+        match programming_entries
+            .iter_mut()
+            .find(|p| p.title == program.title)
+        {
+            Some(programming_entry) => {
+                if !programming_entry
+                    .date_entries
+                    .iter()
+                    .any(|d| d.date == date_entry.date)
+                {
+                    programming_entry.date_entries.push(date_entry);
+                }
+            }
+            None => programming_entries.push(ProgrammingEntry {
+                title: program.title,
+                date_entries: vec![date_entry],
+                poster_url: program.poster_url,
+                genres: split_genres(&program.genres),
+            }),
+        }
     }
 
-    Ok(())
+    Ok(programming_entries)
+}
+
+/// Splits the genres of a program, stored separated by `/`
+fn split_genres(genres: &str) -> Vec<String> {
+    genres
+        .split('/')
+        .filter(|g| !g.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 async fn fetch_latest_newsletter(
     db_connection: &DatabaseConnection,
-) -> anyhow::Result<(NewsletterEntry, MessageId)> {
+) -> anyhow::Result<(NewsletterEntry, entity::newsletter::Model)> {
+    // newsletters awaiting approval aren't in the channel yet
     let latest_newsletter = entity::newsletter::Entity::find()
+        .filter(entity::newsletter::Column::MessageId.is_not_null())
         .order_by_desc(entity::newsletter::Column::CreatedAt)
         .one(db_connection)
         .await
         .context("Could not fetch latest newsletter from db")?
         .ok_or(anyhow!("No newsletters in db"))?;
 
-    let newsletter_programs = latest_newsletter
+    let newsletter = fetch_newsletter_entry(db_connection, &latest_newsletter).await?;
+
+    Ok((newsletter, latest_newsletter))
+}
+
+/// Loads the programs and screenings of a saved newsletter
+async fn fetch_newsletter_entry(
+    db_connection: &DatabaseConnection,
+    newsletter: &entity::newsletter::Model,
+) -> anyhow::Result<NewsletterEntry> {
+    let newsletter_programs = newsletter
         .find_related(entity::program::Entity)
         .all(db_connection)
         .await
@@ -396,6 +931,8 @@ async fn fetch_latest_newsletter(
         .zip(program_entries)
         .map(|(program, entries)| ProgrammingEntry {
             title: program.title,
+            poster_url: program.poster_url,
+            genres: split_genres(&program.genres),
             date_entries: entries
                 .into_iter()
                 .map(|e| DateEntry {
@@ -406,26 +943,19 @@ async fn fetch_latest_newsletter(
         })
         .collect();
 
-    let newsletter = NewsletterEntry {
+    Ok(NewsletterEntry {
         programming_entries,
-        newsletter_link: latest_newsletter.link,
-    };
-
-    Ok((
-        newsletter,
-        MessageId(
-            latest_newsletter
-                .message_id
-                .ok_or(anyhow!("Message id for newsletter is not set"))?,
-        ),
-    ))
+        newsletter_link: newsletter.link.clone(),
+    })
 }
 
+/// Label of the Crontap schedule calling `/update`
+const BOT_SCHEDULE_LABEL: &str = "bot_schedule";
+
 async fn update_schedules(
     state: Arc<ServerState>,
     newsletter_entry: NewsletterEntry,
 ) -> anyhow::Result<()> {
-    const BOT_SCHEDULE_LABEL: &str = "bot_schedule";
     let schedules = state
         .crontap_client
         .list_schedules(
@@ -541,81 +1071,47 @@ async fn update_schedules(
     Ok(())
 }
 
-fn make_message(newsletter_entry: &NewsletterEntry) -> String {
-    let entries_text = newsletter_entry
-        .programming_entries
-        .iter()
-        .map(format_programming_entry)
-        .join("\n\n");
-    format!(
-        "\
-_Nuovi film in arrivo allo Spazio Alfieri\\!_
-
-{}
-
-[👉 Apri nel browser 🔗]({})
-    ",
-        entries_text, newsletter_entry.newsletter_link
-    )
+/// Renders a newsletter for a target, split into as many messages as needed
+fn make_messages(
+    state: &ServerState,
+    target: &targets::Target,
+    newsletter_entry: &NewsletterEntry,
+) -> anyhow::Result<Vec<String>> {
+    make_messages_within(state, target, newsletter_entry, messages::MESSAGE_LENGTH_LIMIT)
 }
 
-fn format_programming_entry(entry: &ProgrammingEntry) -> String {
-    let mut formats_with_dates = entry
-        .date_entries
-        .iter()
-        .map(|date_entry| {
-            let human_readable_date = date_entry.date.format("%d/%m/%Y");
-            let human_readable_time = date_entry.date.format("%H:%M");
-
-            let strikethrough = {
-                if Utc::now() > date_entry.date {
-                    "~"
-                } else {
-                    ""
-                }
-            };
-
-            // todo: make clock emoji represent time
-            let formatted = format!(
-                " {}• 📆 {} 🕔 {}{}{}",
-                strikethrough,
-                human_readable_date,
-                human_readable_time,
-                date_entry
-                    .additional_details
-                    .as_ref()
-                    .map(|info| format!(" _{}_", markdown::escape(info)))
-                    .as_deref()
-                    .unwrap_or(""),
-                strikethrough,
-            );
-
-            (formatted, date_entry.date)
-        })
-        .collect::<Vec<_>>();
-
-    let nearest_date = formats_with_dates
-        .iter_mut()
-        .find(|(_, date)| Utc::now() <= *date);
+fn make_messages_within(
+    state: &ServerState,
+    target: &targets::Target,
+    newsletter_entry: &NewsletterEntry,
+    limit: usize,
+) -> anyhow::Result<Vec<String>> {
+    let (header, films, footer) = state
+        .templates
+        .read()
+        .unwrap()
+        .render_newsletter(
+            target.layout,
+            target.language,
+            target.update_options,
+            &target.filter_newsletter(newsletter_entry),
+        )
+        .context("Unable to render newsletter")?;
 
-    if let Some((formatted, _)) = nearest_date {
-        *formatted = format!("{} 🔔", formatted)
-    }
+    Ok(messages::split_message(&header, &films, &footer, limit))
+}
 
-    let formatted_dates = formats_with_dates
-        .into_iter()
-        .map(|(formatted, _)| formatted)
-        .join("\n");
-
-    format!(
-        "\
-*{}*
-Prossime date:
-{}
-    ",
-        markdown::escape(&entry.title),
-        formatted_dates
-    )
+/// Renders a film with its screenings, using the same template as the newsletter posts
+fn format_programming_entry(
+    state: &ServerState,
+    entry: &ProgrammingEntry,
+) -> anyhow::Result<String> {
+    state
+        .templates
+        .read()
+        .unwrap()
+        .render_programming_entry(locale::Language::Italian, entry)
+        .context("Unable to render film")
 }
 
 async fn persist_newsletter_entry(
@@ -629,6 +1125,9 @@ async fn persist_newsletter_entry(
             link: ActiveValue::Set(newsletter_entry.newsletter_link.clone()),
             message_id: Default::default(),
             created_at: Default::default(),
+            preview_message_id: Default::default(),
+            publish_at: Default::default(),
+            pinned_message_id: Default::default(),
         };
 
         newsletter
@@ -646,6 +1145,8 @@ async fn persist_newsletter_entry(
                     id: ActiveValue::NotSet,
                     newsletter_id: newsletter.id.clone(),
                     title: ActiveValue::Set(e.title.clone()),
+                    poster_url: ActiveValue::Set(e.poster_url.clone()),
+                    genres: ActiveValue::Set(e.genres.join("/")),
                 };
 
                 let date_entries: Vec<_> = e
@@ -0,0 +1,662 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Context;
+use itertools::Itertools;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+    QueryOrder,
+};
+use teloxide::prelude::*;
+use teloxide::types::{MessageId, ParseMode};
+use teloxide::{ApiError, RequestError};
+use tracing::{error, info, warn};
+
+use crate::targets::{Target, MAIN_TARGET};
+use crate::ServerState;
+
+/// Maximum length of a Telegram message
+pub const MESSAGE_LENGTH_LIMIT: usize = 4096;
+
+/// Attempts of a Telegram request before giving up on rate limits and network errors
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before retrying a request after a network error, doubled on each further attempt
+const NETWORK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Runs a Telegram request, waiting as long as Telegram asks when rate limited (`RetryAfter`) and
+/// retrying network errors with exponential backoff, up to [`MAX_ATTEMPTS`] times
+pub async fn with_retries<T, F, Fut>(mut request: F) -> Result<T, RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut attempt = 1;
+    loop {
+        let delay = match request().await {
+            Err(RequestError::RetryAfter(seconds)) if attempt < MAX_ATTEMPTS => {
+                warn!("Rate limited by Telegram, retrying after {}", seconds);
+                seconds.duration()
+            }
+            Err(RequestError::Network(e)) if attempt < MAX_ATTEMPTS => {
+                let delay = NETWORK_RETRY_DELAY * 2u32.pow(attempt - 1);
+                warn!("Network error, retrying in {:?}: {:#}", delay, e);
+                delay
+            }
+            result => return result,
+        };
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Sends a MarkdownV2 message, retrying on rate limits and network errors
+pub async fn send_markdown(
+    state: &ServerState,
+    chat_id: ChatId,
+    text: &str,
+) -> Result<Message, RequestError> {
+    with_retries(|| {
+        state
+            .bot
+            .send_message(chat_id, text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .send()
+    })
+    .await
+}
+
+/// Edits a MarkdownV2 message, retrying on rate limits and network errors.
+///
+/// Unchanged messages count as edited; returns `false` if the message doesn't exist anymore,
+/// e.g. because it was deleted by hand.
+pub async fn edit_markdown(
+    state: &ServerState,
+    chat_id: ChatId,
+    message_id: MessageId,
+    text: &str,
+) -> Result<bool, RequestError> {
+    let result = with_retries(|| {
+        state
+            .bot
+            .edit_message_text(chat_id, message_id, text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .send()
+    })
+    .await;
+
+    match result {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(true),
+        Err(RequestError::Api(ApiError::MessageToEditNotFound)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Length of a message as counted by Telegram, in UTF-16 code units.
+///
+/// MarkdownV2 markup is counted as well, so this overestimates the length of the parsed text.
+fn message_length(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Joins `header`, `blocks` and `footer` with blank lines, splitting the result into messages
+/// within `limit`; an empty header or footer is left out.
+///
+/// Messages are split between blocks, so that MarkdownV2 entities are never broken; a block that
+/// doesn't fit in a message by itself is split between its lines, which must be self-contained,
+/// and a line that doesn't fit either is split as described in [`split_line`].
+pub fn split_message(header: &str, blocks: &[String], footer: &str, limit: usize) -> Vec<String> {
+    let pieces = std::iter::once(header.to_string())
+        .chain(blocks.iter().flat_map(|b| split_block(b, limit)))
+        .chain(std::iter::once(footer.to_string()))
+        .filter(|piece| !piece.is_empty());
+
+    let mut messages: Vec<String> = Vec::new();
+    for piece in pieces {
+        match messages.last_mut() {
+            Some(message) if message_length(message) + 2 + message_length(&piece) <= limit => {
+                message.push_str("\n\n");
+                message.push_str(&piece);
+            }
+            _ => messages.push(piece),
+        }
+    }
+
+    messages
+}
+
+fn split_block(block: &str, limit: usize) -> Vec<String> {
+    if message_length(block) <= limit {
+        return vec![block.to_string()];
+    }
+
+    let lines = block.lines().flat_map(|line| {
+        if message_length(line) <= limit {
+            vec![line.to_string()]
+        } else {
+            split_line(line, limit)
+        }
+    });
+
+    let mut chunks: Vec<String> = Vec::new();
+    for line in lines {
+        match chunks.last_mut() {
+            Some(chunk) if message_length(chunk) + 1 + message_length(&line) <= limit => {
+                chunk.push('\n');
+                chunk.push_str(&line);
+            }
+            _ => chunks.push(line),
+        }
+    }
+
+    chunks
+}
+
+/// MarkdownV2 markers opening and closing an entity, longest first
+const ENTITY_MARKERS: [&str; 6] = ["||", "__", "*", "_", "~", "`"];
+
+/// A piece of a MarkdownV2 line that can't be split, i.e. a character, an escape sequence,
+/// an entity marker or a whole link
+struct LineToken<'a> {
+    text: &'a str,
+    /// Markers of the entities open after this token, outermost first
+    open_entities: Vec<&'static str>,
+}
+
+fn tokenize_line(line: &str) -> Vec<LineToken<'_>> {
+    let mut tokens = Vec::new();
+    let mut open_entities: Vec<&'static str> = Vec::new();
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        let in_code = open_entities.last() == Some(&"`");
+        let length = if c == '\\' {
+            1 + rest[1..].chars().next().map_or(0, char::len_utf8)
+        } else if let Some(marker) = ENTITY_MARKERS
+            .iter()
+            .find(|m| rest.starts_with(**m) && (!in_code || **m == "`"))
+        {
+            match open_entities.iter().rposition(|open| open == marker) {
+                Some(position) => {
+                    open_entities.remove(position);
+                }
+                None => open_entities.push(marker),
+            }
+            marker.len()
+        } else if c == '[' && !in_code {
+            link_length(rest).unwrap_or(1)
+        } else {
+            c.len_utf8()
+        };
+
+        tokens.push(LineToken {
+            text: &rest[..length],
+            open_entities: open_entities.clone(),
+        });
+        rest = &rest[length..];
+    }
+
+    tokens
+}
+
+/// Length of the `[text](url)` link at the start of `text`, if any
+fn link_length(text: &str) -> Option<usize> {
+    let mut escaped = false;
+    let mut in_url = false;
+    let mut previous = None;
+
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '(' if previous == Some(']') => in_url = true,
+            ')' if in_url => return Some(index + 1),
+            _ => {}
+        }
+        previous = Some(c);
+    }
+
+    None
+}
+
+/// Splits a single line longer than `limit`, preferably at a space outside any MarkdownV2 entity.
+///
+/// If an entity doesn't fit in a message by itself, it is split at a space if possible, closing it
+/// at the end of a chunk and opening it again at the start of the next one; links are never split.
+fn split_line(line: &str, limit: usize) -> Vec<String> {
+    let tokens = tokenize_line(line);
+    let opening = |index: usize| match index {
+        0 => String::new(),
+        _ => tokens[index - 1].open_entities.concat(),
+    };
+    let closing = |index: usize| tokens[index - 1].open_entities.iter().rev().join("");
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < tokens.len() {
+        let mut length = message_length(&opening(start));
+        let (mut end, mut safe_end, mut space_end, mut word_end) = (start, None, None, None);
+
+        while end < tokens.len() {
+            length += message_length(tokens[end].text);
+            if length + message_length(&closing(end + 1)) > limit {
+                break;
+            }
+
+            end += 1;
+            let before_space = tokens.get(end).is_some_and(|t| t.text == " ");
+            if before_space {
+                word_end = Some(end);
+            }
+            if tokens[end - 1].open_entities.is_empty() {
+                safe_end = Some(end);
+                if before_space {
+                    space_end = Some(end);
+                }
+            }
+        }
+
+        if end < tokens.len() {
+            // a single token over the limit, e.g. a very long link, gets a chunk of its own
+            end = space_end
+                .or(safe_end)
+                .or(word_end)
+                .unwrap_or(end)
+                .max(start + 1);
+        }
+
+        let text = tokens[start..end].iter().map(|t| t.text).join("");
+        chunks.push(format!("{}{}{}", opening(start), text, closing(end)));
+
+        start = end;
+        while tokens.get(start).is_some_and(|t| t.text == " ") {
+            start += 1;
+        }
+    }
+
+    chunks
+}
+
+/// Message ids of the messages of a newsletter published to `target`, in order
+pub async fn newsletter_message_ids(
+    db_connection: &DatabaseConnection,
+    target: &Target,
+    newsletter_id: i32,
+) -> anyhow::Result<Vec<MessageId>> {
+    let messages = entity::newsletter_message::Entity::find()
+        .filter(entity::newsletter_message::Column::NewsletterId.eq(newsletter_id))
+        .filter(entity::newsletter_message::Column::Target.eq(&target.name))
+        .order_by_asc(entity::newsletter_message::Column::Position)
+        .all(db_connection)
+        .await
+        .context("Unable to fetch newsletter messages")?;
+
+    Ok(messages
+        .into_iter()
+        .map(|m| MessageId(m.message_id))
+        .collect())
+}
+
+/// Sends the messages of a newsletter to `target`, replacing the message ids stored for it.
+///
+/// If a message can't be sent, the ones already sent are deleted, so that a newsletter is never
+/// left half-published.
+pub async fn send_newsletter_messages(
+    state: &ServerState,
+    target: &Target,
+    newsletter_id: i32,
+    texts: &[String],
+) -> anyhow::Result<Vec<MessageId>> {
+    let mut message_ids = Vec::new();
+    for text in texts {
+        match send_markdown(state, target.chat_id, text).await {
+            Ok(message) => message_ids.push(message.id),
+            Err(e) => {
+                for message_id in message_ids {
+                    delete_message(state, target.chat_id, message_id).await;
+                }
+                return Err(e).context("Unable to send newsletter message");
+            }
+        }
+    }
+
+    entity::newsletter_message::Entity::delete_many()
+        .filter(entity::newsletter_message::Column::NewsletterId.eq(newsletter_id))
+        .filter(entity::newsletter_message::Column::Target.eq(&target.name))
+        .exec(&state.db_connection)
+        .await
+        .context("Unable to delete previous newsletter messages")?;
+    save_newsletter_messages(&state.db_connection, target, newsletter_id, 0, &message_ids).await?;
+
+    Ok(message_ids)
+}
+
+async fn save_newsletter_messages(
+    db_connection: &DatabaseConnection,
+    target: &Target,
+    newsletter_id: i32,
+    first_position: usize,
+    message_ids: &[MessageId],
+) -> anyhow::Result<()> {
+    if message_ids.is_empty() {
+        return Ok(());
+    }
+
+    let messages = message_ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| entity::newsletter_message::ActiveModel {
+            id: ActiveValue::NotSet,
+            newsletter_id: ActiveValue::Set(newsletter_id),
+            position: ActiveValue::Set((first_position + i) as i32),
+            message_id: ActiveValue::Set(id.0),
+            target: ActiveValue::Set(target.name.clone()),
+        })
+        .collect_vec();
+
+    entity::newsletter_message::Entity::insert_many(messages)
+        .exec(db_connection)
+        .await
+        .context("Unable to save newsletter messages")?;
+
+    Ok(())
+}
+
+/// Edits the messages of a newsletter published to `target` to show `texts`.
+///
+/// When the newsletter now needs more messages, the missing ones are sent after the last
+/// message in the chat; messages that are no longer needed are deleted. Messages that can't be
+/// found are sent again. Nothing is sent if the newsletter was never published to `target`, e.g.
+/// because the target was added later.
+pub async fn edit_newsletter_messages(
+    state: &ServerState,
+    target: &Target,
+    newsletter_id: i32,
+    texts: &[String],
+) -> anyhow::Result<()> {
+    let messages = entity::newsletter_message::Entity::find()
+        .filter(entity::newsletter_message::Column::NewsletterId.eq(newsletter_id))
+        .filter(entity::newsletter_message::Column::Target.eq(&target.name))
+        .order_by_asc(entity::newsletter_message::Column::Position)
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch newsletter messages")?;
+    if messages.is_empty() {
+        return Ok(());
+    }
+
+    for (message, text) in messages.iter().zip(texts) {
+        let edited = edit_markdown(state, target.chat_id, MessageId(message.message_id), text)
+            .await
+            .context("Unable to update message")?;
+        if !edited {
+            repost_newsletter_message(state, target, message, text).await?;
+        }
+    }
+
+    if texts.len() > messages.len() {
+        let mut message_ids = Vec::new();
+        for text in &texts[messages.len()..] {
+            let message = send_markdown(state, target.chat_id, text)
+                .await
+                .context("Unable to send newsletter message")?;
+            message_ids.push(message.id);
+        }
+
+        save_newsletter_messages(
+            &state.db_connection,
+            target,
+            newsletter_id,
+            messages.len(),
+            &message_ids,
+        )
+        .await?;
+        info!(
+            "Sent {} more messages for newsletter {} to {}",
+            message_ids.len(),
+            newsletter_id,
+            target.name
+        );
+    }
+
+    for message in messages.into_iter().skip(texts.len()) {
+        delete_message(state, target.chat_id, MessageId(message.message_id)).await;
+        entity::newsletter_message::Entity::delete_by_id(message.id)
+            .exec(&state.db_connection)
+            .await
+            .context("Unable to delete newsletter message")?;
+    }
+
+    Ok(())
+}
+
+/// Sends again a newsletter message that can't be edited because it no longer exists
+async fn repost_newsletter_message(
+    state: &ServerState,
+    target: &Target,
+    message: &entity::newsletter_message::Model,
+    text: &str,
+) -> anyhow::Result<()> {
+    let new_message = send_markdown(state, target.chat_id, text)
+        .await
+        .context("Unable to send again newsletter message")?;
+    info!(
+        "Message {} of newsletter {} not found in {}, sent again as {}",
+        message.message_id, message.newsletter_id, target.name, new_message.id
+    );
+
+    let mut updated_message: entity::newsletter_message::ActiveModel = message.clone().into();
+    updated_message.message_id = ActiveValue::Set(new_message.id.0);
+    updated_message
+        .update(&state.db_connection)
+        .await
+        .context("Unable to update newsletter message")?;
+
+    // the first message in the channel is the one attachments reply to, and gets pinned
+    if target.name == MAIN_TARGET && message.position == 0 {
+        let newsletter = entity::newsletter::ActiveModel {
+            id: ActiveValue::Unchanged(message.newsletter_id),
+            message_id: ActiveValue::Set(Some(new_message.id.0)),
+            ..Default::default()
+        };
+        newsletter
+            .update(&state.db_connection)
+            .await
+            .context("Unable to update newsletter with message id")?;
+    }
+
+    Ok(())
+}
+
+/// Pins the first message of the latest newsletter in the channel, without notifying members,
+/// and unpins the messages of the other newsletters, so that exactly one newsletter is pinned.
+///
+/// Pinned messages are tracked in the `newsletter` table, so nothing is sent when the pins are
+/// already up to date.
+pub async fn pin_latest_newsletter(state: &ServerState) -> anyhow::Result<()> {
+    let channel_id = state.main_target().chat_id;
+    let latest_newsletter = entity::newsletter::Entity::find()
+        .filter(entity::newsletter::Column::MessageId.is_not_null())
+        .order_by_desc(entity::newsletter::Column::CreatedAt)
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch latest newsletter")?;
+    let pinned_newsletters = entity::newsletter::Entity::find()
+        .filter(entity::newsletter::Column::PinnedMessageId.is_not_null())
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch pinned newsletters")?;
+
+    let message_id = latest_newsletter.as_ref().and_then(|n| n.message_id);
+    for newsletter in pinned_newsletters {
+        if newsletter.pinned_message_id == message_id {
+            continue;
+        }
+
+        // the message might have been deleted, e.g. when the newsletter was republished
+        if let Some(pinned_message_id) = newsletter.pinned_message_id {
+            let result = with_retries(|| {
+                state
+                    .bot
+                    .unpin_chat_message(channel_id)
+                    .message_id(MessageId(pinned_message_id))
+                    .send()
+            })
+            .await;
+            if let Err(e) = result {
+                error!(
+                    "Unable to unpin channel message {}: {:#}",
+                    pinned_message_id, e
+                );
+            }
+        }
+
+        let mut newsletter: entity::newsletter::ActiveModel = newsletter.into();
+        newsletter.pinned_message_id = ActiveValue::Set(None);
+        newsletter
+            .update(&state.db_connection)
+            .await
+            .context("Unable to update newsletter pin")?;
+    }
+
+    let Some(latest_newsletter) = latest_newsletter else {
+        return Ok(());
+    };
+    if latest_newsletter.pinned_message_id == message_id {
+        return Ok(());
+    }
+    let Some(message_id) = message_id else {
+        return Ok(());
+    };
+
+    with_retries(|| {
+        state
+            .bot
+            .pin_chat_message(channel_id, MessageId(message_id))
+            .disable_notification(true)
+            .send()
+    })
+    .await
+    .context("Unable to pin newsletter message")?;
+
+    let newsletter_id = latest_newsletter.id;
+    let mut latest_newsletter: entity::newsletter::ActiveModel = latest_newsletter.into();
+    latest_newsletter.pinned_message_id = ActiveValue::Set(Some(message_id));
+    latest_newsletter
+        .update(&state.db_connection)
+        .await
+        .context("Unable to update newsletter pin")?;
+
+    info!(
+        "Pinned message {} of newsletter {}",
+        message_id, newsletter_id
+    );
+    Ok(())
+}
+
+/// Deletes a message sent by the bot, logging failures since the message might have already
+/// been deleted by hand
+pub async fn delete_message(state: &ServerState, chat_id: ChatId, message_id: MessageId) {
+    let result = with_retries(|| state.bot.delete_message(chat_id, message_id).send()).await;
+
+    if let Err(e) = result {
+        error!(
+            "Unable to delete message {} in {}: {:#}",
+            message_id, chat_id, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use teloxide::types::Seconds;
+    use teloxide::{ApiError, RequestError};
+
+    use crate::messages::{split_message, with_retries};
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried() {
+        let attempts = AtomicU32::new(0);
+        let result = with_retries(|| async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(RequestError::RetryAfter(Seconds::from_seconds(0))),
+                _ => Ok("sent"),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "sent");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = with_retries(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(RequestError::Api(ApiError::MessageNotModified))
+        })
+        .await;
+        assert!(matches!(
+            result,
+            Err(RequestError::Api(ApiError::MessageNotModified))
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn messages_are_split_between_blocks() {
+        let blocks = vec![
+            "*Primo*\n • 25/09".to_string(),
+            "*Secondo*\n • 26/09".to_string(),
+            "*Terzo*\n • 27/09\n • 28/09\n • 29/09".to_string(),
+        ];
+
+        assert_eq!(
+            split_message("_Titolo_", &blocks, "[link](url)", 4096),
+            vec!["_Titolo_\n\n*Primo*\n • 25/09\n\n*Secondo*\n • 26/09\n\n*Terzo*\n • 27/09\n • 28/09\n • 29/09\n\n[link](url)"]
+        );
+        assert_eq!(
+            split_message("_Titolo_", &blocks, "[link](url)", 40),
+            vec![
+                "_Titolo_\n\n*Primo*\n • 25/09",
+                "*Secondo*\n • 26/09",
+                "*Terzo*\n • 27/09\n • 28/09\n • 29/09",
+                "[link](url)",
+            ]
+        );
+        assert_eq!(
+            split_message("_Titolo_", &blocks, "[link](url)", 20),
+            vec![
+                "_Titolo_",
+                "*Primo*\n • 25/09",
+                "*Secondo*\n • 26/09",
+                "*Terzo*\n • 27/09",
+                " • 28/09\n • 29/09",
+                "[link](url)",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split_outside_entities() {
+        let blocks = vec![
+            "*Terzo film con un titolo lunghissimo* e una descrizione ancora più lunga".to_string(),
+            "aa *bb cc dd ee ff gg hh* ii [link lungo](http://example.com) jj".to_string(),
+        ];
+
+        assert_eq!(
+            split_message("_Titolo_", &blocks, "[link](url)", 20),
+            vec![
+                "_Titolo_",
+                "*Terzo film con un*",
+                "*titolo lunghissimo*",
+                "e una descrizione",
+                "ancora più lunga\n\naa",
+                "*bb cc dd ee ff gg*",
+                "*hh* ii",
+                "[link lungo](http://example.com)",
+                "jj\n\n[link](url)",
+            ]
+        );
+    }
+}
@@ -20,6 +20,8 @@ pub struct NewsletterEntry {
 pub struct ProgrammingEntry {
     pub title: String,
     pub date_entries: Vec<DateEntry>,
+    pub poster_url: Option<String>,
+    pub genres: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -130,6 +132,9 @@ fn parse_html(dom: Html, date_boundaries: Vec<DateTime<Tz>>) -> anyhow::Result<N
     let title_selector = Selector::parse(r#"div div div table tbody tr td table tbody tr td table tbody tr td table tbody tr td table tbody tr td h1"#)
         .map_err(|_| anyhow!("Invalid selector for title"))?;
 
+    let poster_selector =
+        Selector::parse("img").map_err(|_| anyhow!("Invalid selector for poster"))?;
+
     let mut entries = Vec::new();
 
     let title_nodes = dom.select(&title_selector).collect::<Vec<_>>();
@@ -197,9 +202,22 @@ fn parse_html(dom: Html, date_boundaries: Vec<DateTime<Tz>>) -> anyhow::Result<N
             }
         }
 
+        let poster_url = enclosing_box
+            .select(&poster_selector)
+            .filter_map(|img| img.attr("src"))
+            .find(|src| src.starts_with("http"))
+            .map(str::to_string);
+
+        let genres = enclosing_box
+            .text()
+            .find_map(parse_genres)
+            .unwrap_or_default();
+
         entries.push(ProgrammingEntry {
             title: title.to_string(),
             date_entries,
+            poster_url,
+            genres,
         });
     }
 
@@ -227,6 +245,22 @@ fn month_name_to_number(name: &str) -> anyhow::Result<u32> {
     }
 }
 
+/// Parses the genres out of a film details line, e.g. "biografico/drammatico, 100 minuti, Francia 2024"
+fn parse_genres(line: &str) -> Option<Vec<String>> {
+    let mut fields = line.trim().split(',');
+    let genres = fields.next()?;
+    let duration = fields.next()?.trim();
+    duration.strip_suffix("minuti")?.trim().parse::<u32>().ok()?;
+
+    Some(
+        genres
+            .split('/')
+            .map(|g| g.trim().to_lowercase())
+            .filter(|g| !g.is_empty())
+            .collect(),
+    )
+}
+
 fn parse_date_entry(
     pair: Pair<Rule>,
     lower_bound: DateTime<Tz>,
@@ -379,6 +413,11 @@ mod tests {
                         .with_timezone(&Europe::Rome),
                     additional_details: None,
                 }],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_sindrome_amori_passati_1.jpg"
+                        .to_string(),
+                ),
+                genres: vec!["commedia".to_string()],
             },
             ProgrammingEntry {
                 title: "MARIA MONTESSORI".to_string(),
@@ -450,6 +489,11 @@ mod tests {
                         additional_details: None,
                     },
                 ],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_maria_montessori.jpg"
+                        .to_string(),
+                ),
+                genres: vec!["biografico".to_string(), "drammatico".to_string()],
             },
             ProgrammingEntry {
                 title: "LA BAMBINA SEGRETA".to_string(),
@@ -473,6 +517,11 @@ mod tests {
                         additional_details: None,
                     },
                 ],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_la_bambina_segreta.jpg"
+                        .to_string(),
+                ),
+                genres: vec!["drammatico".to_string()],
             },
             ProgrammingEntry {
                 title: "MAKING OF".to_string(),
@@ -518,6 +567,11 @@ mod tests {
                         additional_details: None,
                     },
                 ],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_making_of.jpg"
+                        .to_string(),
+                ),
+                genres: vec!["drammatico".to_string()],
             },
             ProgrammingEntry {
                 title: "GLORIA MUNDI".to_string(),
@@ -527,6 +581,11 @@ mod tests {
                         .with_timezone(&Europe::Rome),
                     additional_details: None,
                 }],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_gloria_mundi.jpg"
+                        .to_string(),
+                ),
+                genres: Vec::new(),
             },
             ProgrammingEntry {
                 title: "CUORI LIBERI".to_string(),
@@ -544,6 +603,11 @@ mod tests {
                         additional_details: None,
                     },
                 ],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_cuori_liberi.jpg"
+                        .to_string(),
+                ),
+                genres: vec!["documentario".to_string()],
             },
             ProgrammingEntry {
                 title: "LA MOGLIE DELL'AVIATORE".to_string(),
@@ -565,6 +629,11 @@ mod tests {
                         ),
                     },
                 ],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_moglie_aviatore.jpg"
+                        .to_string(),
+                ),
+                genres: Vec::new(),
             },
             ProgrammingEntry {
                 title: "MARIUS E JEANNETTE".to_string(),
@@ -574,6 +643,11 @@ mod tests {
                         .with_timezone(&Europe::Rome),
                     additional_details: None,
                 }],
+                poster_url: Some(
+                    "https://media.squalomail.net/users/6534/images/Spazio_Alfieri_marius_jeanette.jpg"
+                        .to_string(),
+                ),
+                genres: Vec::new(),
             },
         ];
 
@@ -0,0 +1,429 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Datelike, Timelike};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+    QuerySelect,
+};
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::parser::ProgrammingEntry;
+use crate::ServerState;
+
+const CALLBACK_PREFIX: &str = "preferences:";
+
+/// Maximum length of callback data accepted by Telegram
+const MAX_CALLBACK_DATA_LENGTH: usize = 64;
+
+/// Labels and hour ranges (start inclusive, end exclusive) of the time slots, one bit each
+const TIME_SLOTS: [(&str, u32, u32); 3] = [
+    ("Fino alle 18", 0, 18),
+    ("18-21", 18, 21),
+    ("Dalle 21", 21, 24),
+];
+
+/// Labels of the weekdays from Monday, one bit each
+const WEEKDAYS: [&str; 7] = ["Lun", "Mar", "Mer", "Gio", "Ven", "Sab", "Dom"];
+
+const ALL_TIME_SLOTS: i32 = (1 << TIME_SLOTS.len()) - 1;
+const ALL_WEEKDAYS: i32 = (1 << WEEKDAYS.len()) - 1;
+
+/// Filters applied to the digests and reminders of a chat
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preferences {
+    pub original_version_only: bool,
+    /// Bitmask of the allowed [`TIME_SLOTS`]
+    pub time_slots: i32,
+    /// Bitmask of the allowed [`WEEKDAYS`]
+    pub weekdays: i32,
+    pub excluded_genres: Vec<String>,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            original_version_only: false,
+            time_slots: ALL_TIME_SLOTS,
+            weekdays: ALL_WEEKDAYS,
+            excluded_genres: Vec::new(),
+        }
+    }
+}
+
+impl Preferences {
+    /// Loads the preferences of a chat, or the default ones if it never set them
+    pub async fn load(
+        db_connection: &DatabaseConnection,
+        chat_id: ChatId,
+    ) -> anyhow::Result<Preferences> {
+        let preference = entity::preference::Entity::find()
+            .filter(entity::preference::Column::ChatId.eq(chat_id.0))
+            .one(db_connection)
+            .await
+            .context("Unable to fetch preferences")?;
+
+        Ok(preference
+            .map(|p| Preferences {
+                original_version_only: p.original_version_only,
+                time_slots: p.time_slots,
+                weekdays: p.weekdays,
+                excluded_genres: p
+                    .excluded_genres
+                    .split('/')
+                    .filter(|g| !g.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            })
+            .unwrap_or_default())
+    }
+
+    async fn save(
+        &self,
+        db_connection: &DatabaseConnection,
+        chat_id: ChatId,
+    ) -> anyhow::Result<()> {
+        let existing_preference = entity::preference::Entity::find()
+            .filter(entity::preference::Column::ChatId.eq(chat_id.0))
+            .one(db_connection)
+            .await
+            .context("Unable to fetch preferences")?;
+
+        entity::preference::ActiveModel {
+            id: existing_preference
+                .map(|p| ActiveValue::Unchanged(p.id))
+                .unwrap_or(ActiveValue::NotSet),
+            chat_id: ActiveValue::Set(chat_id.0),
+            original_version_only: ActiveValue::Set(self.original_version_only),
+            time_slots: ActiveValue::Set(self.time_slots),
+            weekdays: ActiveValue::Set(self.weekdays),
+            excluded_genres: ActiveValue::Set(self.excluded_genres.join("/")),
+        }
+        .save(db_connection)
+        .await
+        .context("Unable to save preferences")?;
+
+        Ok(())
+    }
+
+    /// Whether a screening at `date`, with the given additional details, matches the preferences
+    pub fn allows_screening(&self, date: DateTime<Tz>, details: Option<&str>) -> bool {
+        let weekday = date.weekday().num_days_from_monday() as usize;
+        let time_slot = TIME_SLOTS
+            .iter()
+            .position(|(_, start, end)| (*start..*end).contains(&date.hour()));
+
+        (!self.original_version_only || is_original_version(details))
+            && self.weekdays & (1 << weekday) != 0
+            && time_slot.is_some_and(|slot| self.time_slots & (1 << slot) != 0)
+    }
+
+    /// Keeps the films and screenings matching the preferences
+    pub fn filter(&self, entries: Vec<ProgrammingEntry>) -> Vec<ProgrammingEntry> {
+        entries
+            .into_iter()
+            .filter(|e| !e.genres.iter().any(|g| self.excluded_genres.contains(g)))
+            .map(|mut e| {
+                e.date_entries
+                    .retain(|d| self.allows_screening(d.date, d.additional_details.as_deref()));
+                e
+            })
+            .filter(|e| !e.date_entries.is_empty())
+            .collect()
+    }
+}
+
+fn is_original_version(details: Option<&str>) -> bool {
+    details.is_some_and(|d| {
+        let details = d.to_lowercase();
+        details.contains("versione originale") || details.contains("lingua originale")
+    })
+}
+
+/// Actions of the preferences inline keyboard, encoded as `preferences:<action>[:<argument>]`
+/// callback data
+enum PreferenceAction {
+    ToggleOriginalVersion,
+    ToggleTimeSlot(usize),
+    ToggleWeekday(usize),
+    ToggleGenre(String),
+    Reset,
+}
+
+impl PreferenceAction {
+    fn to_callback_data(&self) -> String {
+        match self {
+            PreferenceAction::ToggleOriginalVersion => format!("{}ov", CALLBACK_PREFIX),
+            PreferenceAction::ToggleTimeSlot(slot) => format!("{}slot:{}", CALLBACK_PREFIX, slot),
+            PreferenceAction::ToggleWeekday(day) => format!("{}day:{}", CALLBACK_PREFIX, day),
+            PreferenceAction::ToggleGenre(genre) => format!("{}genre:{}", CALLBACK_PREFIX, genre),
+            PreferenceAction::Reset => format!("{}reset", CALLBACK_PREFIX),
+        }
+    }
+
+    fn from_callback_data(data: &str) -> anyhow::Result<Self> {
+        let action = data
+            .strip_prefix(CALLBACK_PREFIX)
+            .ok_or(anyhow!("Invalid preferences callback data: '{}'", data))?;
+        let parse_index = |index: &str, max: usize| {
+            index
+                .parse::<usize>()
+                .ok()
+                .filter(|i| *i < max)
+                .ok_or(anyhow!("Invalid index in callback data '{}'", data))
+        };
+
+        match action.split_once(':') {
+            None if action == "ov" => Ok(PreferenceAction::ToggleOriginalVersion),
+            None if action == "reset" => Ok(PreferenceAction::Reset),
+            Some(("slot", slot)) => Ok(PreferenceAction::ToggleTimeSlot(parse_index(
+                slot,
+                TIME_SLOTS.len(),
+            )?)),
+            Some(("day", day)) => Ok(PreferenceAction::ToggleWeekday(parse_index(
+                day,
+                WEEKDAYS.len(),
+            )?)),
+            Some(("genre", genre)) => Ok(PreferenceAction::ToggleGenre(genre.to_string())),
+            _ => Err(anyhow!("Unknown preferences action: '{}'", action)),
+        }
+    }
+
+    fn apply(self, preferences: &mut Preferences) {
+        match self {
+            PreferenceAction::ToggleOriginalVersion => {
+                preferences.original_version_only = !preferences.original_version_only
+            }
+            PreferenceAction::ToggleTimeSlot(slot) => preferences.time_slots ^= 1 << slot,
+            PreferenceAction::ToggleWeekday(day) => preferences.weekdays ^= 1 << day,
+            PreferenceAction::ToggleGenre(genre) => {
+                if let Some(index) = preferences.excluded_genres.iter().position(|g| *g == genre) {
+                    preferences.excluded_genres.remove(index);
+                } else {
+                    preferences.excluded_genres.push(genre);
+                }
+            }
+            PreferenceAction::Reset => *preferences = Preferences::default(),
+        }
+    }
+}
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    Update::filter_callback_query()
+        .filter(|q: CallbackQuery| {
+            q.data
+                .as_deref()
+                .is_some_and(|d| d.starts_with(CALLBACK_PREFIX))
+        })
+        .endpoint(handle_callback)
+}
+
+/// Text and inline keyboard of the `/preferenze` settings menu
+pub async fn preferences_message(
+    state: &ServerState,
+    chat_id: ChatId,
+) -> anyhow::Result<(String, InlineKeyboardMarkup)> {
+    let preferences = Preferences::load(&state.db_connection, chat_id).await?;
+    let keyboard = preferences_keyboard(state, &preferences).await?;
+
+    Ok((
+        "Promemoria e riepiloghi giornalieri includeranno solo le proiezioni che corrispondono \
+        alle tue preferenze. Tocca un'opzione per cambiarla."
+            .to_string(),
+        keyboard,
+    ))
+}
+
+async fn preferences_keyboard(
+    state: &ServerState,
+    preferences: &Preferences,
+) -> anyhow::Result<InlineKeyboardMarkup> {
+    let check = |enabled: bool| if enabled { "✅" } else { "⬜" };
+    let button = |text: String, action: PreferenceAction| {
+        InlineKeyboardButton::callback(text, action.to_callback_data())
+    };
+
+    let mut rows = vec![vec![button(
+        format!(
+            "{} Solo versione originale",
+            check(preferences.original_version_only)
+        ),
+        PreferenceAction::ToggleOriginalVersion,
+    )]];
+
+    rows.push(
+        TIME_SLOTS
+            .iter()
+            .enumerate()
+            .map(|(slot, (label, _, _))| {
+                button(
+                    format!(
+                        "{} {}",
+                        check(preferences.time_slots & (1 << slot) != 0),
+                        label
+                    ),
+                    PreferenceAction::ToggleTimeSlot(slot),
+                )
+            })
+            .collect(),
+    );
+
+    let weekday_buttons = WEEKDAYS
+        .iter()
+        .enumerate()
+        .map(|(day, label)| {
+            button(
+                format!(
+                    "{} {}",
+                    check(preferences.weekdays & (1 << day) != 0),
+                    label
+                ),
+                PreferenceAction::ToggleWeekday(day),
+            )
+        })
+        .collect_vec();
+    rows.extend(weekday_buttons.chunks(4).map(<[_]>::to_vec));
+
+    let genre_buttons = known_genres(&state.db_connection)
+        .await?
+        .into_iter()
+        .chain(preferences.excluded_genres.iter().cloned())
+        .unique()
+        .sorted()
+        .filter(|genre| {
+            PreferenceAction::ToggleGenre(genre.clone())
+                .to_callback_data()
+                .len()
+                <= MAX_CALLBACK_DATA_LENGTH
+        })
+        .map(|genre| {
+            let excluded = preferences.excluded_genres.contains(&genre);
+            let text = format!("{} {}", if excluded { "🚫" } else { "✅" }, genre);
+            button(text, PreferenceAction::ToggleGenre(genre))
+        })
+        .collect_vec();
+    rows.extend(genre_buttons.chunks(3).map(<[_]>::to_vec));
+
+    rows.push(vec![button(
+        "↩️ Ripristina".to_string(),
+        PreferenceAction::Reset,
+    )]);
+
+    Ok(InlineKeyboardMarkup::new(rows))
+}
+
+/// Genres of all the films in the database
+async fn known_genres(db_connection: &DatabaseConnection) -> anyhow::Result<Vec<String>> {
+    let genres: Vec<String> = entity::program::Entity::find()
+        .select_only()
+        .column(entity::program::Column::Genres)
+        .distinct()
+        .into_tuple()
+        .all(db_connection)
+        .await
+        .context("Unable to fetch genres")?;
+
+    Ok(genres
+        .iter()
+        .flat_map(|g| g.split('/'))
+        .filter(|g| !g.is_empty())
+        .map(str::to_string)
+        .unique()
+        .collect())
+}
+
+async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let action = PreferenceAction::from_callback_data(q.data.as_deref().unwrap_or_default())?;
+    let chat_id = ChatId::from(q.from.id);
+
+    let mut preferences = Preferences::load(&state.db_connection, chat_id).await?;
+    action.apply(&mut preferences);
+    preferences.save(&state.db_connection, chat_id).await?;
+
+    bot.answer_callback_query(q.id.clone()).await?;
+
+    if let Some(message) = q.regular_message() {
+        bot.edit_message_reply_markup(message.chat.id, message.id)
+            .reply_markup(preferences_keyboard(&state, &preferences).await?)
+            .await
+            .context("Unable to update preferences message")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use chrono_tz::Europe;
+
+    use crate::parser::{DateEntry, ProgrammingEntry};
+    use crate::preferences::Preferences;
+
+    fn date_entry(date: &str, additional_details: Option<&str>) -> DateEntry {
+        DateEntry {
+            date: DateTime::parse_from_rfc3339(date)
+                .unwrap()
+                .with_timezone(&Europe::Rome),
+            additional_details: additional_details.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn screenings_are_filtered_by_preferences() {
+        let entries = vec![
+            ProgrammingEntry {
+                title: "LA SINDROME DEGLI AMORI PASSATI".to_string(),
+                date_entries: vec![date_entry("2024-09-25T17:00:00+02:00", None)],
+                poster_url: None,
+                genres: vec!["commedia".to_string()],
+            },
+            ProgrammingEntry {
+                title: "MAKING OF".to_string(),
+                date_entries: vec![
+                    // friday
+                    date_entry(
+                        "2024-09-27T19:00:00+02:00",
+                        Some("—  versione originale con sottotitoli"),
+                    ),
+                    // saturday
+                    date_entry("2024-09-28T21:15:00+02:00", None),
+                    // tuesday
+                    date_entry(
+                        "2024-10-01T21:15:00+02:00",
+                        Some("—  versione originale con sottotitoli"),
+                    ),
+                ],
+                poster_url: None,
+                genres: vec!["drammatico".to_string()],
+            },
+        ];
+
+        let preferences = Preferences {
+            original_version_only: true,
+            // from 18 onwards
+            time_slots: 0b110,
+            // every day but tuesday
+            weekdays: 0b1111101,
+            excluded_genres: vec!["commedia".to_string()],
+        };
+
+        let filtered = preferences.filter(entries.clone());
+
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].title, "MAKING OF");
+        assert_eq!(
+            filtered[0].date_entries,
+            vec![entries[1].date_entries[0].clone()]
+        );
+        assert_eq!(Preferences::default().filter(entries.clone()), entries);
+    }
+}
@@ -0,0 +1,410 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use chrono_tz::Europe;
+use itertools::Itertools;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, LoaderTrait, ModelTrait, QueryFilter,
+    QueryOrder,
+};
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode};
+use teloxide::utils::markdown;
+use tracing::{error, info};
+
+use crate::formatting::clock_emoji;
+use crate::parser::ProgrammingEntry;
+use crate::preferences::Preferences;
+use crate::ServerState;
+
+const CALLBACK_PREFIX: &str = "reminder:";
+
+/// How often pending reminders are checked
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Actions of the reminder inline keyboards, encoded as `reminder:<action>:<id>` callback data
+enum ReminderAction {
+    /// Show the upcoming screenings of the film with the given program id
+    ChooseScreening(i32),
+    /// Add a reminder for the entry with the given id
+    Add(i32),
+    /// Cancel the reminder with the given id
+    Cancel(i32),
+}
+
+impl ReminderAction {
+    fn to_callback_data(&self) -> String {
+        match self {
+            ReminderAction::ChooseScreening(id) => format!("{}film:{}", CALLBACK_PREFIX, id),
+            ReminderAction::Add(id) => format!("{}add:{}", CALLBACK_PREFIX, id),
+            ReminderAction::Cancel(id) => format!("{}cancel:{}", CALLBACK_PREFIX, id),
+        }
+    }
+
+    fn from_callback_data(data: &str) -> anyhow::Result<Self> {
+        let (action, id) = data
+            .strip_prefix(CALLBACK_PREFIX)
+            .and_then(|d| d.split_once(':'))
+            .ok_or(anyhow!("Invalid reminder callback data: '{}'", data))?;
+        let id =
+            i32::from_str(id).with_context(|| format!("Invalid id in callback data '{}'", data))?;
+
+        match action {
+            "film" => Ok(ReminderAction::ChooseScreening(id)),
+            "add" => Ok(ReminderAction::Add(id)),
+            "cancel" => Ok(ReminderAction::Cancel(id)),
+            _ => Err(anyhow!("Unknown reminder action: '{}'", action)),
+        }
+    }
+}
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    Update::filter_callback_query()
+        .filter(|q: CallbackQuery| {
+            q.data
+                .as_deref()
+                .is_some_and(|d| d.starts_with(CALLBACK_PREFIX))
+        })
+        .endpoint(handle_callback)
+}
+
+/// Inline keyboard with a "🔔 Ricordamelo" button for each film with upcoming screenings
+pub async fn film_keyboard(
+    state: &ServerState,
+    entries: &[ProgrammingEntry],
+) -> anyhow::Result<InlineKeyboardMarkup> {
+    let now = Utc::now();
+    let titles = entries
+        .iter()
+        .filter(|e| e.date_entries.iter().any(|d| d.date > now))
+        .map(|e| e.title.clone())
+        .collect_vec();
+
+    let programs = entity::program::Entity::find()
+        .filter(entity::program::Column::Title.is_in(titles.clone()))
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch programs")?;
+
+    let buttons = titles
+        .into_iter()
+        .filter_map(|title| {
+            let program = programs.iter().find(|p| p.title == title)?;
+            Some(vec![InlineKeyboardButton::callback(
+                format!("🔔 Ricordamelo: {}", title),
+                ReminderAction::ChooseScreening(program.id).to_callback_data(),
+            )])
+        })
+        .collect_vec();
+
+    Ok(InlineKeyboardMarkup::new(buttons))
+}
+
+async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let action = ReminderAction::from_callback_data(q.data.as_deref().unwrap_or_default())?;
+    let chat_id = ChatId::from(q.from.id);
+
+    match action {
+        ReminderAction::ChooseScreening(program_id) => {
+            bot.answer_callback_query(q.id).await?;
+            send_screening_choice(&bot, &state, chat_id, program_id).await?;
+        }
+        ReminderAction::Add(entry_id) => {
+            let answer = add_reminder(&state, chat_id, entry_id).await?;
+            bot.answer_callback_query(q.id).text(answer).await?;
+        }
+        ReminderAction::Cancel(reminder_id) => {
+            entity::reminder::Entity::delete_many()
+                .filter(entity::reminder::Column::Id.eq(reminder_id))
+                .filter(entity::reminder::Column::ChatId.eq(chat_id.0))
+                .exec(&state.db_connection)
+                .await
+                .context("Unable to delete reminder")?;
+            bot.answer_callback_query(q.id.clone())
+                .text("Promemoria annullato")
+                .await?;
+
+            if let Some(message) = q.regular_message() {
+                let (text, keyboard) = reminders_message(&state, chat_id).await?;
+                bot.edit_message_text(message.chat.id, message.id, text)
+                    .parse_mode(ParseMode::MarkdownV2)
+                    .reply_markup(keyboard)
+                    .await
+                    .context("Unable to update reminders message")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_screening_choice(
+    bot: &Bot,
+    state: &ServerState,
+    chat_id: ChatId,
+    program_id: i32,
+) -> anyhow::Result<()> {
+    let program = entity::program::Entity::find_by_id(program_id)
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch program")?
+        .ok_or(anyhow!("No program with id {}", program_id))?;
+
+    let entries = entity::entry::Entity::find()
+        .inner_join(entity::program::Entity)
+        .filter(entity::program::Column::Title.eq(&program.title))
+        .filter(entity::entry::Column::Date.gt(Utc::now()))
+        .order_by_asc(entity::entry::Column::Date)
+        .order_by_desc(entity::entry::Column::Id)
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch upcoming screenings")?;
+    // a screening repeated by several newsletters is offered once, as listed by the most recent one
+    let entries = entries
+        .into_iter()
+        .dedup_by(|a, b| a.date == b.date)
+        .collect_vec();
+
+    if entries.is_empty() {
+        bot.send_message(
+            chat_id,
+            format!(
+                "Non ci sono proiezioni in programma per *{}*",
+                markdown::escape(&program.title)
+            ),
+        )
+        .parse_mode(ParseMode::MarkdownV2)
+        .await?;
+
+        return Ok(());
+    }
+
+    let preferences = Preferences::load(&state.db_connection, chat_id).await?;
+    let entries = entries
+        .into_iter()
+        .filter(|e| {
+            preferences.allows_screening(e.date.with_timezone(&Europe::Rome), e.details.as_deref())
+        })
+        .collect_vec();
+
+    if entries.is_empty() {
+        bot.send_message(
+            chat_id,
+            format!(
+                "Nessuna proiezione di *{}* corrisponde alle tue preferenze, puoi cambiarle con /preferenze",
+                markdown::escape(&program.title)
+            ),
+        )
+        .parse_mode(ParseMode::MarkdownV2)
+        .await?;
+
+        return Ok(());
+    }
+
+    let buttons = entries
+        .iter()
+        .map(|e| {
+            let date = e.date.with_timezone(&Europe::Rome);
+            vec![InlineKeyboardButton::callback(
+                format!(
+                    "📆 {} {} {}",
+                    date.format("%d/%m"),
+                    clock_emoji(&date),
+                    date.format("%H:%M")
+                ),
+                ReminderAction::Add(e.id).to_callback_data(),
+            )]
+        })
+        .collect_vec();
+
+    bot.send_message(
+        chat_id,
+        format!(
+            "Per quale proiezione di *{}* vuoi un promemoria?",
+            markdown::escape(&program.title)
+        ),
+    )
+    .parse_mode(ParseMode::MarkdownV2)
+    .reply_markup(InlineKeyboardMarkup::new(buttons))
+    .await
+    .context("Unable to send screening choice")?;
+
+    Ok(())
+}
+
+/// Adds a reminder and returns the answer to show to the user
+async fn add_reminder(
+    state: &ServerState,
+    chat_id: ChatId,
+    entry_id: i32,
+) -> anyhow::Result<String> {
+    let entry = entity::entry::Entity::find_by_id(entry_id)
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch entry")?
+        .ok_or(anyhow!("No entry with id {}", entry_id))?;
+
+    if entry.date <= Utc::now() {
+        return Ok("Questa proiezione è già passata".to_string());
+    }
+
+    let existing_reminder = entity::reminder::Entity::find()
+        .filter(entity::reminder::Column::ChatId.eq(chat_id.0))
+        .filter(entity::reminder::Column::EntryId.eq(entry_id))
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch reminder")?;
+
+    if existing_reminder.is_none() {
+        entity::reminder::ActiveModel {
+            id: ActiveValue::NotSet,
+            chat_id: ActiveValue::Set(chat_id.0),
+            entry_id: ActiveValue::Set(entry_id),
+            sent: ActiveValue::Set(false),
+        }
+        .insert(&state.db_connection)
+        .await
+        .context("Unable to save reminder")?;
+    }
+
+    let date = entry.date.with_timezone(&Europe::Rome);
+    Ok(format!(
+        "🔔 Ti ricorderò la proiezione del {} alle {}",
+        date.format("%d/%m"),
+        date.format("%H:%M")
+    ))
+}
+
+/// Lists the pending reminders of a user, with a button to cancel each of them
+pub async fn reminders_message(
+    state: &ServerState,
+    chat_id: ChatId,
+) -> anyhow::Result<(String, InlineKeyboardMarkup)> {
+    let reminders = entity::reminder::Entity::find()
+        .filter(entity::reminder::Column::ChatId.eq(chat_id.0))
+        .filter(entity::reminder::Column::Sent.eq(false))
+        .find_also_related(entity::entry::Entity)
+        .filter(entity::entry::Column::Date.gt(Utc::now()))
+        .order_by_asc(entity::entry::Column::Date)
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch reminders")?;
+
+    if reminders.is_empty() {
+        return Ok((
+            markdown::escape(
+                "Non hai promemoria attivi. Usa /oggi, /domani o /settimana per aggiungerne.",
+            ),
+            InlineKeyboardMarkup::default(),
+        ));
+    }
+
+    let (reminders, entries): (Vec<_>, Vec<_>) = reminders
+        .into_iter()
+        .filter_map(|(reminder, entry)| Some((reminder, entry?)))
+        .unzip();
+    let programs = entries
+        .load_one(entity::program::Entity, &state.db_connection)
+        .await
+        .context("Unable to fetch reminder programs")?;
+
+    let mut lines = Vec::new();
+    let mut buttons = Vec::new();
+    for ((reminder, entry), program) in reminders.iter().zip(&entries).zip(programs) {
+        let title = program.map(|p| p.title).unwrap_or_default();
+        let date = entry.date.with_timezone(&Europe::Rome);
+        let human_readable_date = date.format("%d/%m %H:%M").to_string();
+
+        lines.push(format!(
+            " • *{}* \\- {}",
+            markdown::escape(&title),
+            markdown::escape(&human_readable_date)
+        ));
+        buttons.push(vec![InlineKeyboardButton::callback(
+            format!("❌ {} {}", title, human_readable_date),
+            ReminderAction::Cancel(reminder.id).to_callback_data(),
+        )]);
+    }
+
+    Ok((
+        format!("_I tuoi promemoria_\n\n{}", lines.join("\n")),
+        InlineKeyboardMarkup::new(buttons),
+    ))
+}
+
+/// Periodically sends the reminders of screenings starting within the configured lead time
+pub async fn run_scheduler(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = send_due_reminders(&state).await {
+            error!("Unable to send reminders: {:#}", e);
+        }
+    }
+}
+
+async fn send_due_reminders(state: &ServerState) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let due_reminders = entity::reminder::Entity::find()
+        .filter(entity::reminder::Column::Sent.eq(false))
+        .find_also_related(entity::entry::Entity)
+        .filter(entity::entry::Column::Date.gt(now))
+        .filter(entity::entry::Column::Date.lte(now + state.reminder_lead_time))
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch due reminders")?;
+
+    for (reminder, entry) in due_reminders {
+        let Some(entry) = entry else { continue };
+        let program = entry
+            .find_related(entity::program::Entity)
+            .one(&state.db_connection)
+            .await
+            .context("Unable to fetch reminder program")?
+            .ok_or(anyhow!("Entry {} has no program", entry.id))?;
+
+        let date = entry.date.with_timezone(&Europe::Rome);
+        let text = format!(
+            "🔔 *{}* inizia alle {}{}",
+            markdown::escape(&program.title),
+            date.format("%H:%M"),
+            entry
+                .details
+                .as_ref()
+                .map(|info| format!(" _{}_", markdown::escape(info)))
+                .as_deref()
+                .unwrap_or(""),
+        );
+
+        // failed reminders are retried on the next tick, until the screening starts
+        let result = state
+            .bot
+            .send_message(ChatId(reminder.chat_id), text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .await;
+        if let Err(e) = result {
+            error!("Unable to send reminder {}: {:#}", reminder.id, e);
+            continue;
+        }
+
+        info!("Sent reminder {}", reminder.id);
+        let mut reminder: entity::reminder::ActiveModel = reminder.into();
+        reminder.sent = ActiveValue::Set(true);
+        reminder
+            .update(&state.db_connection)
+            .await
+            .context("Unable to mark reminder as sent")?;
+    }
+
+    Ok(())
+}
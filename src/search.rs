@@ -0,0 +1,339 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::{Days, Utc};
+use chrono_tz::Europe;
+use itertools::Itertools;
+use reqwest::Url;
+use sea_orm::sea_query::{Alias, Expr, Func};
+use sea_orm::{ColumnTrait, Condition, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder};
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::{
+    InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText,
+    ParseMode,
+};
+use teloxide::utils::markdown;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::parser::{DateEntry, ProgrammingEntry};
+use crate::ServerState;
+use crate::{fetch_programming_entries_between, format_programming_entry, split_genres};
+
+/// Words ignored when matching titles, so that "sindrome amori" matches "LA SINDROME DEGLI AMORI"
+const STOP_WORDS: [&str; 19] = [
+    "il", "lo", "la", "i", "gli", "le", "l", "un", "uno", "una", "di", "del", "della", "dei",
+    "degli", "delle", "dell", "e", "the",
+];
+
+/// Minimum score for a title to be considered a match
+pub const MATCH_THRESHOLD: f64 = 0.85;
+
+const MAX_RESULTS: usize = 10;
+
+/// Length of the word prefixes used to select the candidate titles in the database
+const CANDIDATE_PREFIX_LENGTH: usize = 3;
+
+/// Maximum number of results accepted by Telegram for an inline query
+const MAX_INLINE_RESULTS: usize = 50;
+
+/// A film with its upcoming screenings and the newsletter announcing it
+pub struct FilmCard {
+    pub programming_entry: ProgrammingEntry,
+    pub newsletter_link: String,
+}
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    Update::filter_inline_query().endpoint(handle_inline_query)
+}
+
+/// Lowercases `text`, strips accents and punctuation and removes [`STOP_WORDS`]
+pub fn normalize(text: &str) -> Vec<String> {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .filter(|w| !STOP_WORDS.contains(w))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Scores how well `query` matches `title`, from 0 to 1.
+///
+/// Every word of the query is compared with the most similar word of the title,
+/// so that typos and partial titles still match.
+pub fn match_score(query: &[String], title: &[String]) -> f64 {
+    if query.is_empty() || title.is_empty() {
+        return 0.0;
+    }
+
+    let total: f64 = query
+        .iter()
+        .map(|query_word| {
+            title
+                .iter()
+                .map(|title_word| {
+                    if title_word.starts_with(query_word.as_str()) {
+                        1.0
+                    } else {
+                        strsim::jaro_winkler(query_word, title_word)
+                    }
+                })
+                .fold(0.0, f64::max)
+        })
+        .sum();
+
+    total / query.len() as f64
+}
+
+/// Finds the films whose title matches `query` among all newsletters, best matches first.
+///
+/// Only the titles containing the first letters of some query word are scored: typos rarely
+/// affect the start of a word, and the database does the bulk of the filtering.
+pub async fn search_films(
+    db_connection: &DatabaseConnection,
+    query: &str,
+) -> anyhow::Result<Vec<FilmCard>> {
+    let normalized_query = normalize(query);
+    if normalized_query.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let programs = entity::program::Entity::find()
+        .filter(candidate_titles(&normalized_query))
+        .find_also_related(entity::newsletter::Entity)
+        .order_by_desc(entity::newsletter::Column::CreatedAt)
+        .all(db_connection)
+        .await
+        .context("Unable to fetch programs")?;
+
+    // programs are sorted by newsletter, so the first one of each title is the latest
+    let matches = programs
+        .into_iter()
+        .unique_by(|(program, _)| program.title.clone())
+        .map(|(program, newsletter)| {
+            let score = match_score(&normalized_query, &normalize(&program.title));
+            (program, newsletter, score)
+        })
+        .filter(|(_, _, score)| *score >= MATCH_THRESHOLD)
+        .sorted_by(|(_, _, a), (_, _, b)| b.total_cmp(a))
+        .take(MAX_RESULTS)
+        .collect_vec();
+
+    let titles = matches
+        .iter()
+        .map(|(program, _, _)| program.title.clone())
+        .collect_vec();
+    let entries = entity::entry::Entity::find()
+        .filter(entity::entry::Column::Date.gte(Utc::now()))
+        .order_by_asc(entity::entry::Column::Date)
+        .find_also_related(entity::program::Entity)
+        .filter(entity::program::Column::Title.is_in(titles))
+        .all(db_connection)
+        .await
+        .context("Unable to fetch upcoming screenings")?;
+
+    let results = matches
+        .into_iter()
+        .map(|(program, newsletter, _)| {
+            let date_entries = entries
+                .iter()
+                .filter(|(_, p)| p.as_ref().is_some_and(|p| p.title == program.title))
+                .map(|(e, _)| DateEntry {
+                    date: e.date.with_timezone(&Europe::Rome),
+                    additional_details: e.details.clone(),
+                })
+                // screenings repeated by several newsletters are next to each other
+                .dedup_by(|a, b| a.date == b.date)
+                .collect();
+
+            FilmCard {
+                programming_entry: ProgrammingEntry {
+                    title: program.title,
+                    date_entries,
+                    poster_url: program.poster_url,
+                    genres: split_genres(&program.genres),
+                },
+                newsletter_link: newsletter.map(|n| n.link).unwrap_or_default(),
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+/// Selects the titles containing the first letters of some word of `normalized_query`.
+///
+/// Titles are lowercased and stripped of accents by the database, as done by [`normalize`].
+fn candidate_titles(normalized_query: &[String]) -> Condition {
+    let title = Func::cust(Alias::new("unaccent")).arg(Func::lower(Expr::col((
+        entity::program::Entity,
+        entity::program::Column::Title,
+    ))));
+
+    normalized_query
+        .iter()
+        .fold(Condition::any(), |condition, word| {
+            let prefix: String = word.chars().take(CANDIDATE_PREFIX_LENGTH).collect();
+            condition.add(Expr::expr(title.clone()).like(format!("%{}%", prefix)))
+        })
+}
+
+/// Lists the films with upcoming screenings, sorted by their next screening
+pub async fn upcoming_films(db_connection: &DatabaseConnection) -> anyhow::Result<Vec<FilmCard>> {
+    let now = Utc::now().with_timezone(&Europe::Rome);
+    let entries = fetch_programming_entries_between(db_connection, now, now + Days::new(365))
+        .await
+        .context("Unable to fetch upcoming screenings")?;
+
+    let titles = entries.iter().map(|e| e.title.clone()).collect_vec();
+    let programs = entity::program::Entity::find()
+        .filter(entity::program::Column::Title.is_in(titles))
+        .find_also_related(entity::newsletter::Entity)
+        .order_by_desc(entity::newsletter::Column::CreatedAt)
+        .all(db_connection)
+        .await
+        .context("Unable to fetch programs")?;
+
+    let cards = entries
+        .into_iter()
+        .map(|programming_entry| {
+            // programs are sorted by newsletter, so the first one of each title is the latest
+            let newsletter_link = programs
+                .iter()
+                .find(|(program, _)| program.title == programming_entry.title)
+                .and_then(|(_, newsletter)| newsletter.as_ref())
+                .map(|n| n.link.clone())
+                .unwrap_or_default();
+
+            FilmCard {
+                programming_entry,
+                newsletter_link,
+            }
+        })
+        .collect();
+
+    Ok(cards)
+}
+
+pub fn format_film_card(state: &ServerState, result: &FilmCard) -> anyhow::Result<String> {
+    let dates = if result.programming_entry.date_entries.is_empty() {
+        format!(
+            "*{}*\n{}\n",
+            markdown::escape(&result.programming_entry.title),
+            markdown::escape("Nessuna proiezione in programma.")
+        )
+    } else {
+        format_programming_entry(state, &result.programming_entry)?
+    };
+
+    // an empty link is rejected by Telegram
+    if result.newsletter_link.is_empty() {
+        return Ok(dates);
+    }
+
+    Ok(format!(
+        "{}\n[👉 Apri la newsletter 🔗]({})",
+        dates,
+        markdown::escape_link_url(&result.newsletter_link)
+    ))
+}
+
+async fn handle_inline_query(
+    bot: Bot,
+    q: InlineQuery,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let results = if q.query.trim().is_empty() {
+        upcoming_films(&state.db_connection).await?
+    } else {
+        search_films(&state.db_connection, &q.query).await?
+    };
+
+    let articles = results
+        .iter()
+        .take(MAX_INLINE_RESULTS)
+        .enumerate()
+        .map(|(index, result)| {
+            let next_date = result
+                .programming_entry
+                .date_entries
+                .first()
+                .map(|d| format!("Prossima proiezione: {}", d.date.format("%d/%m %H:%M")))
+                .unwrap_or_else(|| "Nessuna proiezione in programma".to_string());
+
+            let mut article = InlineQueryResultArticle::new(
+                index.to_string(),
+                result.programming_entry.title.clone(),
+                InputMessageContent::Text(
+                    InputMessageContentText::new(format_film_card(&state, result)?)
+                        .parse_mode(ParseMode::MarkdownV2),
+                ),
+            )
+            .description(next_date);
+
+            let poster_url = result
+                .programming_entry
+                .poster_url
+                .as_deref()
+                .and_then(|url| Url::parse(url).ok());
+            if let Some(poster_url) = poster_url {
+                article = article.thumbnail_url(poster_url);
+            }
+
+            Ok(InlineQueryResult::Article(article))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    bot.answer_inline_query(q.id, articles)
+        .cache_time(60)
+        .await
+        .context("Unable to answer inline query")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::sea_query::PostgresQueryBuilder;
+    use sea_orm::{EntityTrait, QueryFilter, QueryTrait};
+
+    use crate::search::{candidate_titles, match_score, normalize, MATCH_THRESHOLD};
+
+    #[test]
+    fn titles_are_normalized() {
+        assert_eq!(
+            normalize("LA MOGLIE DELL'AVIATORE"),
+            vec!["moglie".to_string(), "aviatore".to_string()]
+        );
+        assert_eq!(
+            normalize("Perché sì"),
+            vec!["perche".to_string(), "si".to_string()]
+        );
+    }
+
+    #[test]
+    fn partial_and_misspelled_queries_match() {
+        let title = normalize("LA SINDROME DEGLI AMORI PASSATI");
+
+        assert!(match_score(&normalize("sindrome amori"), &title) >= MATCH_THRESHOLD);
+        assert!(match_score(&normalize("sindorme"), &title) >= MATCH_THRESHOLD);
+        assert!(match_score(&normalize("montessori"), &title) < MATCH_THRESHOLD);
+    }
+
+    #[test]
+    fn accented_titles_match_unaccented_queries() {
+        let query = normalize("perche");
+
+        let sql = entity::program::Entity::find()
+            .filter(candidate_titles(&query))
+            .into_query()
+            .to_string(PostgresQueryBuilder);
+        assert!(sql.contains(r#"unaccent(LOWER("program"."title")) LIKE '%per%'"#));
+
+        assert!(match_score(&query, &normalize("Perché sì")) >= MATCH_THRESHOLD);
+    }
+}
@@ -0,0 +1,263 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Utc};
+use chrono_tz::{Europe, Tz};
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, Condition, EntityTrait, QueryFilter};
+use teloxide::prelude::*;
+use teloxide::{ApiError, RequestError};
+use tracing::{error, info};
+
+use crate::commands::{screenings_message, send_screenings, start_of_day};
+use crate::preferences::Preferences;
+use crate::{fetch_programming_entries_between, report_error, ServerState};
+
+/// How often pending digests are checked
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60);
+
+const DEFAULT_DIGEST_TIME: NaiveTime = NaiveTime::from_hms_opt(9, 0, 0).unwrap();
+
+/// Screenings listed by a digest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestContent {
+    Today,
+    Tomorrow,
+    Week,
+}
+
+impl DigestContent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestContent::Today => "oggi",
+            DigestContent::Tomorrow => "domani",
+            DigestContent::Week => "settimana",
+        }
+    }
+
+    /// Heading and time span of the screenings to list, starting from `today`
+    pub fn period(
+        &self,
+        today: NaiveDate,
+    ) -> anyhow::Result<(&'static str, DateTime<Tz>, DateTime<Tz>)> {
+        let (heading, from, days) = match self {
+            DigestContent::Today => ("Oggi allo Spazio Alfieri", today, 1),
+            DigestContent::Tomorrow => ("Domani allo Spazio Alfieri", today + Days::new(1), 1),
+            DigestContent::Week => ("Questa settimana allo Spazio Alfieri", today, 7),
+        };
+
+        Ok((
+            heading,
+            start_of_day(from)?,
+            start_of_day(from + Days::new(days))?,
+        ))
+    }
+}
+
+impl FromStr for DigestContent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oggi" => Ok(DigestContent::Today),
+            "domani" => Ok(DigestContent::Tomorrow),
+            "settimana" => Ok(DigestContent::Week),
+            _ => Err(anyhow!("Unknown digest content: '{}'", s)),
+        }
+    }
+}
+
+/// Parses the arguments of `/abbonati`, i.e. an optional `HH:MM` time and an optional
+/// [`DigestContent`], in any order
+pub fn parse_subscription_args(args: &str) -> anyhow::Result<(NaiveTime, DigestContent)> {
+    let mut digest_time = None;
+    let mut content = None;
+
+    for arg in args.split_whitespace() {
+        let arg = arg.to_lowercase();
+        if let Ok(time) = NaiveTime::parse_from_str(&arg, "%H:%M") {
+            if digest_time.replace(time).is_some() {
+                return Err(anyhow!("Digest time given twice"));
+            }
+        } else if content.replace(DigestContent::from_str(&arg)?).is_some() {
+            return Err(anyhow!("Digest content given twice"));
+        }
+    }
+
+    Ok((
+        digest_time.unwrap_or(DEFAULT_DIGEST_TIME),
+        content.unwrap_or(DigestContent::Today),
+    ))
+}
+
+/// Subscribes a chat to the daily digest, replacing its previous subscription if any
+pub async fn subscribe(
+    state: &ServerState,
+    chat_id: ChatId,
+    digest_time: NaiveTime,
+    content: DigestContent,
+) -> anyhow::Result<()> {
+    let now = Utc::now().with_timezone(&Europe::Rome);
+    // a digest whose time has already passed today starts tomorrow
+    let last_sent_on = (digest_time <= now.time()).then_some(now.date_naive());
+
+    let existing_subscription = entity::subscription::Entity::find()
+        .filter(entity::subscription::Column::ChatId.eq(chat_id.0))
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch subscription")?;
+
+    entity::subscription::ActiveModel {
+        id: existing_subscription
+            .map(|s| ActiveValue::Unchanged(s.id))
+            .unwrap_or(ActiveValue::NotSet),
+        chat_id: ActiveValue::Set(chat_id.0),
+        digest_time: ActiveValue::Set(digest_time),
+        content: ActiveValue::Set(content.as_str().to_string()),
+        last_sent_on: ActiveValue::Set(last_sent_on),
+    }
+    .save(&state.db_connection)
+    .await
+    .context("Unable to save subscription")?;
+
+    Ok(())
+}
+
+/// Removes the subscription of a chat, returning whether it was subscribed
+pub async fn unsubscribe(state: &ServerState, chat_id: ChatId) -> anyhow::Result<bool> {
+    let result = entity::subscription::Entity::delete_many()
+        .filter(entity::subscription::Column::ChatId.eq(chat_id.0))
+        .exec(&state.db_connection)
+        .await
+        .context("Unable to delete subscription")?;
+
+    Ok(result.rows_affected > 0)
+}
+
+/// Periodically sends the digests whose time has come
+pub async fn run_scheduler(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = send_due_digests(&state).await {
+            error!("Unable to send digests: {:#}", e);
+        }
+    }
+}
+
+async fn send_due_digests(state: &ServerState) -> anyhow::Result<()> {
+    let now = Utc::now().with_timezone(&Europe::Rome);
+    let today = now.date_naive();
+
+    let due_subscriptions = entity::subscription::Entity::find()
+        .filter(entity::subscription::Column::DigestTime.lte(now.time()))
+        .filter(
+            Condition::any()
+                .add(entity::subscription::Column::LastSentOn.is_null())
+                .add(entity::subscription::Column::LastSentOn.lt(today)),
+        )
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch due subscriptions")?;
+
+    for subscription in due_subscriptions {
+        let subscription_id = subscription.id;
+        if let Err(e) = send_digest(state, subscription.clone(), today).await {
+            report_error(
+                state,
+                &format!("Got error while sending digest {}", subscription_id),
+                e,
+            )
+            .await;
+
+            // a broken subscription is reported once a day, rather than at every tick
+            if let Err(e) = mark_digest_sent(state, subscription, today).await {
+                error!("Unable to mark digest {} as sent: {:#}", subscription_id, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_digest(
+    state: &ServerState,
+    subscription: entity::subscription::Model,
+    today: NaiveDate,
+) -> anyhow::Result<()> {
+    let content = DigestContent::from_str(&subscription.content)?;
+    let (heading, from, to) = content.period(today)?;
+    let entries = fetch_programming_entries_between(&state.db_connection, from, to)
+        .await
+        .context("Unable to fetch screenings")?;
+    let preferences = Preferences::load(&state.db_connection, ChatId(subscription.chat_id)).await?;
+    let entries = preferences.filter(entries);
+
+    // days without screenings are skipped, rather than spamming the chat
+    if !entries.is_empty() {
+        let (texts, keyboard) = screenings_message(state, heading, &entries).await?;
+        let result = send_screenings(state, ChatId(subscription.chat_id), &texts, keyboard).await;
+
+        match result {
+            Ok(_) => info!("Sent digest for subscription {}", subscription.id),
+            Err(RequestError::Api(
+                ApiError::BotKicked | ApiError::BotKickedFromSupergroup | ApiError::ChatNotFound,
+            )) => {
+                info!(
+                    "Removing subscription {}, the bot is no longer in the chat",
+                    subscription.id
+                );
+                unsubscribe(state, ChatId(subscription.chat_id)).await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e).context("Unable to send digest"),
+        }
+    }
+
+    mark_digest_sent(state, subscription, today).await
+}
+
+async fn mark_digest_sent(
+    state: &ServerState,
+    subscription: entity::subscription::Model,
+    today: NaiveDate,
+) -> anyhow::Result<()> {
+    let mut subscription: entity::subscription::ActiveModel = subscription.into();
+    subscription.last_sent_on = ActiveValue::Set(Some(today));
+    subscription
+        .update(&state.db_connection)
+        .await
+        .context("Unable to mark digest as sent")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use crate::subscriptions::{parse_subscription_args, DigestContent};
+
+    #[test]
+    fn subscription_args_are_parsed() {
+        assert_eq!(
+            parse_subscription_args("").unwrap(),
+            (
+                NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                DigestContent::Today
+            )
+        );
+        assert_eq!(
+            parse_subscription_args("Settimana 8:30").unwrap(),
+            (
+                NaiveTime::from_hms_opt(8, 30, 0).unwrap(),
+                DigestContent::Week
+            )
+        );
+        assert!(parse_subscription_args("domani dopodomani").is_err());
+        assert!(parse_subscription_args("25:00").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use teloxide::types::ChatId;
+
+use crate::locale::Language;
+use crate::parser::NewsletterEntry;
+use crate::preferences::Preferences;
+use crate::templates::{Layout, UpdateOptions};
+
+/// Name of the target publishing to `CHANNEL_ID`
+pub const MAIN_TARGET: &str = "channel";
+
+/// A chat where newsletters are published, with its own formatting and filters
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    /// Name identifying the messages of the target in the `newsletter_message` table
+    pub name: String,
+    pub chat_id: ChatId,
+    pub layout: Layout,
+    pub language: Language,
+    pub update_options: UpdateOptions,
+    /// Films and screenings published to the target
+    pub filter: Preferences,
+}
+
+impl Target {
+    pub fn new(name: &str, chat_id: ChatId) -> Self {
+        Target {
+            name: name.to_string(),
+            chat_id,
+            layout: Default::default(),
+            language: Default::default(),
+            update_options: Default::default(),
+            filter: Default::default(),
+        }
+    }
+
+    /// Keeps the films and screenings of a newsletter allowed by the filter of the target
+    pub fn filter_newsletter(&self, newsletter_entry: &NewsletterEntry) -> NewsletterEntry {
+        NewsletterEntry {
+            programming_entries: self
+                .filter
+                .filter(newsletter_entry.programming_entries.clone()),
+            newsletter_link: newsletter_entry.newsletter_link.clone(),
+        }
+    }
+}
+
+/// Parses a target written as `name=chat_id`, followed by space-separated `key=value` options:
+///
+/// - `layout`: `films` or `calendar`
+/// - `language`: `it` or `en`
+/// - `update`: comma-separated [`UpdateOptions`]
+/// - `filter`: `original-version`, to publish only original-version screenings
+/// - `exclude-genres`: comma-separated genres of the films not to publish
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let (name, chat_id) = fields
+            .next()
+            .and_then(|f| f.split_once('='))
+            .ok_or(anyhow!("Target '{}' must start with name=chat_id", s))?;
+        if name == MAIN_TARGET {
+            bail!("Target name '{}' is reserved for CHANNEL_ID", MAIN_TARGET);
+        }
+        let chat_id = i64::from_str(chat_id)
+            .with_context(|| format!("Unable to parse chat id '{}' into i64", chat_id))?;
+
+        let mut target = Target::new(name, ChatId(chat_id));
+        for field in fields {
+            let (key, value) = field.split_once('=').ok_or(anyhow!(
+                "Target option '{}' must be written as key=value",
+                field
+            ))?;
+
+            match key {
+                "layout" => target.layout = Layout::from_str(value)?,
+                "language" => target.language = Language::from_str(value)?,
+                "update" => target.update_options = UpdateOptions::from_str(value)?,
+                "filter" => match value {
+                    "original-version" => target.filter.original_version_only = true,
+                    _ => bail!("Unknown target filter '{}'", value),
+                },
+                "exclude-genres" => {
+                    target.filter.excluded_genres =
+                        value.split(',').map(|g| g.to_lowercase()).collect()
+                }
+                _ => bail!("Unknown target option '{}'", key),
+            }
+        }
+
+        Ok(target)
+    }
+}
+
+/// Parses the `;`-separated targets of `PUBLICATION_TARGETS`
+pub fn parse_targets(raw: &str) -> anyhow::Result<Vec<Target>> {
+    let targets = raw
+        .split(';')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(Target::from_str)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    for (i, target) in targets.iter().enumerate() {
+        if targets[..i].iter().any(|t| t.name == target.name) {
+            bail!("Duplicate target name '{}'", target.name);
+        }
+    }
+
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::ChatId;
+
+    use crate::locale::Language;
+    use crate::targets::{parse_targets, Target};
+    use crate::templates::Layout;
+
+    #[test]
+    fn targets_are_parsed() {
+        let targets = parse_targets(
+            "english=-1001234 language=en filter=original-version; \
+            test=-1005678 layout=calendar update=collapse-past exclude-genres=Horror;",
+        )
+        .unwrap();
+
+        let mut english = Target::new("english", ChatId(-1001234));
+        english.language = Language::English;
+        english.filter.original_version_only = true;
+        let mut test = Target::new("test", ChatId(-1005678));
+        test.layout = Layout::Calendar;
+        test.update_options.collapse_past = true;
+        test.filter.excluded_genres = vec!["horror".to_string()];
+        assert_eq!(targets, vec![english, test]);
+
+        assert!(parse_targets("channel=-1001234").is_err());
+        assert!(parse_targets("a=1; a=2").is_err());
+        assert!(parse_targets("a=1 layout=list").is_err());
+    }
+}
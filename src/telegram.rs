@@ -0,0 +1,62 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use teloxide::stop::{mk_stop_token, StopToken};
+use teloxide::types::Update;
+use teloxide::update_listeners::{StatefulListener, UpdateListener};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::error;
+
+use crate::{inbound, ServerState};
+
+pub type UpdateSender = mpsc::UnboundedSender<Result<Update, Infallible>>;
+
+/// Creates an update listener fed by [`receive_update`], so that the dispatcher can receive
+/// Telegram webhooks through the same axum router as the rest of the bot.
+pub fn webhook_listener() -> (UpdateSender, impl UpdateListener<Err = Infallible>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    // the listener is never stopped, the dispatcher lives as long as the server
+    let (stop_token, _) = mk_stop_token();
+
+    fn stream<S>(state: &mut (S, StopToken)) -> &mut S {
+        &mut state.0
+    }
+
+    let listener = StatefulListener::new(
+        (UnboundedReceiverStream::new(receiver), stop_token),
+        stream,
+        |state: &mut (_, StopToken)| state.1.clone(),
+    );
+
+    (sender, listener)
+}
+
+/// Receives Telegram webhook updates, authenticated by the secret token set with `setWebhook`.
+pub async fn receive_update(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    let secret_token = headers
+        .get("X-Telegram-Bot-Api-Secret-Token")
+        .and_then(|v| v.to_str().ok());
+    if !secret_token.is_some_and(|t| inbound::secrets_match(t, &state.telegram_webhook_secret)) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match serde_json::from_str::<Update>(&body) {
+        Ok(update) => {
+            if state.telegram_updates.send(Ok(update)).is_err() {
+                error!("Update dispatcher is not running");
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+        }
+        // Telegram would retry the same update forever, so it is acknowledged anyway
+        Err(e) => error!("Unable to parse Telegram update: {:#}", e),
+    }
+
+    StatusCode::OK
+}
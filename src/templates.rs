@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use minijinja::value::Value;
+use minijinja::{context, escape_formatter, AutoEscape, Environment};
+use sea_orm::{DatabaseConnection, EntityTrait};
+use serde::Serialize;
+use teloxide::utils::markdown;
+use tracing::info;
+
+use crate::formatting::{clock_emoji, is_late_show};
+use crate::locale::Language;
+use crate::parser::{DateEntry, NewsletterEntry, ProgrammingEntry};
+
+/// Name of the template with the layout of the newsletter posts
+pub const NEWSLETTER_TEMPLATE: &str = "newsletter";
+/// Name of the template with the day-by-day layout of the newsletter posts
+pub const CALENDAR_TEMPLATE: &str = "calendar";
+
+/// Templates shipped with the bot, which can be overridden by files or database rows with the
+/// same name
+const BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+    (
+        NEWSLETTER_TEMPLATE,
+        include_str!("../templates/newsletter.md"),
+    ),
+    (CALENDAR_TEMPLATE, include_str!("../templates/calendar.md")),
+];
+
+const MARKDOWN_V2: AutoEscape = AutoEscape::Custom("markdownv2");
+
+#[derive(Serialize)]
+struct NewsletterContext {
+    link: String,
+    films: Vec<FilmContext>,
+    /// Films without upcoming screenings, when they are shown in a separate section
+    finished: Vec<FilmContext>,
+}
+
+#[derive(Serialize)]
+struct FilmContext {
+    title: String,
+    genres: Vec<String>,
+    poster_url: Option<String>,
+    screenings: Vec<ScreeningContext>,
+    /// Number of past screenings left out of `screenings`
+    past_screenings: usize,
+    #[serde(skip)]
+    next_date: Option<DateTime<Tz>>,
+}
+
+#[derive(Serialize)]
+struct ScreeningContext {
+    /// Date with abbreviated weekday and month names, e.g. `mer 25 set`
+    date: String,
+    /// Time formatted as `HH:MM`
+    time: String,
+    /// Clock emoji showing the time
+    clock: char,
+    late: bool,
+    details: Option<String>,
+    past: bool,
+    /// Whether this is the first screening that hasn't started yet
+    next: bool,
+}
+
+/// Screenings of a day across all films, for the calendar layout
+#[derive(Serialize)]
+struct DayContext {
+    weekday: String,
+    /// Date formatted as e.g. `25 settembre`
+    date: String,
+    screenings: Vec<DayScreeningContext>,
+    /// Number of past screenings left out of `screenings`
+    past_screenings: usize,
+}
+
+#[derive(Serialize)]
+struct DayScreeningContext {
+    /// Time formatted as `HH:MM`
+    time: String,
+    /// Clock emoji showing the time
+    clock: char,
+    late: bool,
+    title: String,
+    details: Option<String>,
+    past: bool,
+}
+
+/// How newsletter posts are laid out in the channel
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Layout {
+    /// Films with their screenings, in the order of the newsletter
+    #[default]
+    Films,
+    /// Screenings grouped by day, in chronological order
+    Calendar,
+}
+
+impl Layout {
+    fn template_name(&self) -> &'static str {
+        match self {
+            Layout::Films => NEWSLETTER_TEMPLATE,
+            Layout::Calendar => CALENDAR_TEMPLATE,
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "films" => Ok(Layout::Films),
+            "calendar" => Ok(Layout::Calendar),
+            _ => bail!("Unknown layout '{}', expected 'films' or 'calendar'", s),
+        }
+    }
+}
+
+/// How newsletter posts show screenings that have already taken place, as they are updated
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UpdateOptions {
+    /// Replace past screenings with their number, instead of striking them through
+    pub collapse_past: bool,
+    /// Move films without upcoming screenings to a section after the others
+    pub separate_finished: bool,
+    /// Sort films by their next screening, instead of following the newsletter
+    pub sort_by_next: bool,
+}
+
+impl FromStr for UpdateOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = UpdateOptions::default();
+        for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option {
+                "collapse-past" => options.collapse_past = true,
+                "separate-finished" => options.separate_finished = true,
+                "sort-by-next" => options.sort_by_next = true,
+                _ => bail!("Unknown update option '{}'", option),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+impl NewsletterContext {
+    fn new(
+        newsletter_entry: &NewsletterEntry,
+        language: Language,
+        options: UpdateOptions,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let mut films = newsletter_entry
+            .programming_entries
+            .iter()
+            .map(|e| FilmContext::new(e, language, options.collapse_past, now))
+            .collect_vec();
+
+        if options.sort_by_next {
+            // Films without upcoming screenings keep their order, after the others
+            films.sort_by_key(|f| (f.next_date.is_none(), f.next_date));
+        }
+
+        let finished = if options.separate_finished {
+            let (upcoming, finished) = films.into_iter().partition(|f| f.next_date.is_some());
+            films = upcoming;
+            finished
+        } else {
+            vec![]
+        };
+
+        NewsletterContext {
+            link: newsletter_entry.newsletter_link.clone(),
+            films,
+            finished,
+        }
+    }
+}
+
+impl FilmContext {
+    fn new(
+        entry: &ProgrammingEntry,
+        language: Language,
+        collapse_past: bool,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let next_date = entry
+            .date_entries
+            .iter()
+            .map(|e| e.date)
+            .find(|date| now <= *date);
+
+        let (screenings, past_screenings): (Vec<_>, Vec<_>) = entry
+            .date_entries
+            .iter()
+            .map(|e| ScreeningContext::new(e, language, now, Some(e.date) == next_date))
+            .partition(|s| !(collapse_past && s.past));
+
+        FilmContext {
+            title: entry.title.clone(),
+            genres: entry.genres.clone(),
+            poster_url: entry.poster_url.clone(),
+            screenings,
+            past_screenings: past_screenings.len(),
+            next_date,
+        }
+    }
+}
+
+impl DayContext {
+    /// Groups the screenings of a newsletter by day; when `collapse_past` is set, past screenings
+    /// are counted instead, and days that are over are left out.
+    fn from_newsletter(
+        newsletter_entry: &NewsletterEntry,
+        language: Language,
+        collapse_past: bool,
+        now: DateTime<Utc>,
+    ) -> Vec<Self> {
+        newsletter_entry
+            .programming_entries
+            .iter()
+            .flat_map(|p| p.date_entries.iter().map(move |d| (p, d)))
+            .sorted_by_key(|(_, d)| d.date)
+            .chunk_by(|(_, d)| d.date.date_naive())
+            .into_iter()
+            .map(|(date, screenings)| {
+                let (screenings, past_screenings): (Vec<_>, Vec<_>) = screenings
+                    .map(|(p, d)| DayScreeningContext {
+                        time: d.date.format("%H:%M").to_string(),
+                        clock: clock_emoji(&d.date),
+                        late: is_late_show(&d.date),
+                        title: p.title.clone(),
+                        details: d.additional_details.clone(),
+                        past: now > d.date,
+                    })
+                    .partition(|s| !(collapse_past && s.past));
+
+                DayContext {
+                    weekday: language.weekday(&date).to_string(),
+                    date: language.day_and_month(&date),
+                    screenings,
+                    past_screenings: past_screenings.len(),
+                }
+            })
+            .filter(|d| !d.screenings.is_empty())
+            .collect()
+    }
+}
+
+impl ScreeningContext {
+    fn new(date_entry: &DateEntry, language: Language, now: DateTime<Utc>, next: bool) -> Self {
+        ScreeningContext {
+            date: language.short_date(&date_entry.date),
+            time: date_entry.date.format("%H:%M").to_string(),
+            clock: clock_emoji(&date_entry.date),
+            late: is_late_show(&date_entry.date),
+            details: date_entry.additional_details.clone(),
+            past: now > date_entry.date,
+            next,
+        }
+    }
+}
+
+/// Message templates, rendered with [MiniJinja](https://docs.rs/minijinja) into MarkdownV2.
+///
+/// Values are escaped automatically, unless marked with the `safe` filter; link targets are
+/// escaped with the `url` filter instead.
+pub struct Templates {
+    environment: Environment<'static>,
+}
+
+impl Templates {
+    /// Creates the templates shipped with the bot, replacing those in `overrides` by name
+    pub fn new(overrides: HashMap<String, String>) -> anyhow::Result<Self> {
+        let mut environment = Environment::new();
+        environment.set_auto_escape_callback(|_| MARKDOWN_V2);
+        environment.set_formatter(|out, state, value| {
+            if state.auto_escape() != MARKDOWN_V2 || value.is_safe() {
+                return escape_formatter(out, state, value);
+            }
+            if value.is_none() || value.is_undefined() {
+                return Ok(());
+            }
+
+            write!(out, "{}", markdown::escape(&value.to_string()))?;
+            Ok(())
+        });
+        environment.add_filter("url", |url: &str| {
+            Value::from_safe_string(markdown::escape_link_url(url))
+        });
+
+        let mut sources: HashMap<String, String> = BUILTIN_TEMPLATES
+            .iter()
+            .map(|(name, source)| (name.to_string(), source.to_string()))
+            .collect();
+        sources.extend(overrides);
+        for (name, source) in sources {
+            environment
+                .add_template_owned(name.clone(), source)
+                .with_context(|| format!("Invalid template '{}'", name))?;
+        }
+
+        let templates = Templates { environment };
+        templates.check()?;
+
+        Ok(templates)
+    }
+
+    /// Loads the templates from `*.md` files in `directory`, if set, and from the `template`
+    /// table, which takes precedence
+    pub async fn load(
+        directory: Option<&Path>,
+        db_connection: &DatabaseConnection,
+    ) -> anyhow::Result<Self> {
+        let mut overrides = HashMap::new();
+
+        if let Some(directory) = directory {
+            let entries = std::fs::read_dir(directory).with_context(|| {
+                format!("Unable to read templates directory {}", directory.display())
+            })?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_none_or(|e| e != "md") {
+                    continue;
+                }
+
+                let name = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .ok_or(anyhow!("Invalid template file name {}", path.display()))?
+                    .to_string();
+                let source = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Unable to read template {}", path.display()))?;
+                overrides.insert(name, source);
+            }
+        }
+
+        let db_templates = entity::template::Entity::find()
+            .all(db_connection)
+            .await
+            .context("Unable to fetch templates")?;
+        overrides.extend(db_templates.into_iter().map(|t| (t.name, t.source)));
+
+        info!("Loaded {} custom templates", overrides.len());
+        Templates::new(overrides)
+    }
+
+    /// Renders a sample newsletter, so that errors are found when templates are loaded rather
+    /// than when publishing
+    fn check(&self) -> anyhow::Result<()> {
+        let sample = NewsletterEntry {
+            programming_entries: vec![ProgrammingEntry {
+                title: "Film".to_string(),
+                date_entries: vec![DateEntry {
+                    date: Utc::now().with_timezone(&chrono_tz::Europe::Rome),
+                    additional_details: Some("versione originale".to_string()),
+                }],
+                poster_url: None,
+                genres: vec!["drammatico".to_string()],
+            }],
+            newsletter_link: "https://www.spazioalfieri.it".to_string(),
+        };
+
+        let all_options = UpdateOptions {
+            collapse_past: true,
+            separate_finished: true,
+            sort_by_next: true,
+        };
+        for layout in [Layout::Films, Layout::Calendar] {
+            for language in [Language::Italian, Language::English] {
+                for options in [UpdateOptions::default(), all_options] {
+                    self.render_newsletter(layout, language, options, &sample)
+                        .with_context(|| format!("Invalid {} template", layout.template_name()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renders the header, the films or days and the footer of a newsletter post.
+    ///
+    /// Besides their arguments, macros can use the fixed strings in `language` as `strings`.
+    /// Films without upcoming screenings are rendered with the `finished` macro when separated
+    /// by `options`, or with `film` if the template doesn't define it.
+    pub fn render_newsletter(
+        &self,
+        layout: Layout,
+        language: Language,
+        options: UpdateOptions,
+        newsletter_entry: &NewsletterEntry,
+    ) -> anyhow::Result<(String, Vec<String>, String)> {
+        let template = self.environment.get_template(layout.template_name())?;
+        let captured = template.render_captured(context! { strings => language.strings() })?;
+        let state = captured.state();
+        let now = Utc::now();
+        let newsletter = NewsletterContext::new(newsletter_entry, language, options, now);
+
+        let header = state.call_macro("header", &[Value::from_serialize(&newsletter)])?;
+        let blocks = match layout {
+            Layout::Films => {
+                let mut blocks = newsletter
+                    .films
+                    .iter()
+                    .map(|f| state.call_macro("film", &[Value::from_serialize(f)]))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if state.lookup("finished").is_some() {
+                    if !newsletter.finished.is_empty() {
+                        blocks.push(state.call_macro(
+                            "finished",
+                            &[Value::from_serialize(&newsletter.finished)],
+                        )?);
+                    }
+                } else {
+                    for film in &newsletter.finished {
+                        blocks.push(state.call_macro("film", &[Value::from_serialize(film)])?);
+                    }
+                }
+
+                blocks
+            }
+            Layout::Calendar => {
+                DayContext::from_newsletter(newsletter_entry, language, options.collapse_past, now)
+                    .iter()
+                    .map(|d| state.call_macro("day", &[Value::from_serialize(d)]))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+        let footer = state.call_macro("footer", &[Value::from_serialize(&newsletter)])?;
+
+        Ok((header, blocks, footer))
+    }
+
+    /// Renders a film with its screenings, as in newsletter posts
+    pub fn render_programming_entry(
+        &self,
+        language: Language,
+        entry: &ProgrammingEntry,
+    ) -> anyhow::Result<String> {
+        let template = self.environment.get_template(NEWSLETTER_TEMPLATE)?;
+        let captured = template.render_captured(context! { strings => language.strings() })?;
+        let state = captured.state();
+        let film = FilmContext::new(entry, language, false, Utc::now());
+
+        Ok(state.call_macro("film", &[Value::from_serialize(&film)])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::DateTime;
+    use chrono_tz::Europe;
+
+    use crate::locale::Language;
+    use crate::parser::{DateEntry, NewsletterEntry, ProgrammingEntry};
+    use crate::templates::{Layout, Templates, UpdateOptions, NEWSLETTER_TEMPLATE};
+
+    #[test]
+    fn newsletter_is_rendered_with_escaped_values() {
+        let past = DateTime::parse_from_rfc3339("2020-09-25T17:00:00+02:00")
+            .unwrap()
+            .with_timezone(&Europe::Rome);
+        let future = DateTime::parse_from_rfc3339("2030-09-26T22:15:00+02:00")
+            .unwrap()
+            .with_timezone(&Europe::Rome);
+        let newsletter_entry = NewsletterEntry {
+            programming_entries: vec![ProgrammingEntry {
+                title: "L'ultimo film!".to_string(),
+                date_entries: vec![
+                    DateEntry {
+                        date: past,
+                        additional_details: None,
+                    },
+                    DateEntry {
+                        date: future,
+                        additional_details: Some("(versione originale)".to_string()),
+                    },
+                ],
+                poster_url: None,
+                genres: vec![],
+            }],
+            newsletter_link: "https://example.com/newsletter".to_string(),
+        };
+
+        let templates = Templates::new(HashMap::new()).unwrap();
+
+        let (header, films, footer) = templates
+            .render_newsletter(
+                Layout::Films,
+                Language::Italian,
+                UpdateOptions::default(),
+                &newsletter_entry,
+            )
+            .unwrap();
+        assert_eq!(header, "_Nuovi film in arrivo allo Spazio Alfieri\\!_");
+        assert_eq!(
+            films,
+            vec!["*L'ultimo film\\!*\nProssime date:\n ~• 📆 ven 25 set 🕔 17:00~\n • 📆 gio 26 set 🕥 22:15 🌙 _\\(versione originale\\)_ 🔔"]
+        );
+        assert_eq!(
+            footer,
+            "[👉 Apri nel browser 🔗](https://example.com/newsletter)"
+        );
+
+        let (header, films, footer) = templates
+            .render_newsletter(
+                Layout::Films,
+                Language::English,
+                UpdateOptions::default(),
+                &newsletter_entry,
+            )
+            .unwrap();
+        assert_eq!(header, "_New films coming to Spazio Alfieri\\!_");
+        assert_eq!(
+            films,
+            vec!["*L'ultimo film\\!*\nNext screenings:\n ~• 📆 Fri 25 Sep 🕔 17:00~\n • 📆 Thu 26 Sep 🕥 22:15 🌙 _\\(versione originale\\)_ 🔔"]
+        );
+        assert_eq!(
+            footer,
+            "[👉 Open in browser 🔗](https://example.com/newsletter)"
+        );
+    }
+
+    #[test]
+    fn past_screenings_are_collapsed_and_finished_films_separated() {
+        let date = |s: &str| {
+            DateTime::parse_from_rfc3339(s)
+                .unwrap()
+                .with_timezone(&Europe::Rome)
+        };
+        let film = |title: &str, dates: &[&str]| ProgrammingEntry {
+            title: title.to_string(),
+            date_entries: dates
+                .iter()
+                .map(|d| DateEntry {
+                    date: date(d),
+                    additional_details: None,
+                })
+                .collect(),
+            poster_url: None,
+            genres: vec![],
+        };
+        let newsletter_entry = NewsletterEntry {
+            programming_entries: vec![
+                film("Finito", &["2020-09-24T21:00:00+02:00"]),
+                film(
+                    "Ultimi giorni",
+                    &[
+                        "2020-09-25T17:00:00+02:00",
+                        "2020-09-25T21:00:00+02:00",
+                        "2030-09-27T21:00:00+02:00",
+                    ],
+                ),
+                film("Prossimo", &["2030-09-26T18:30:00+02:00"]),
+            ],
+            newsletter_link: "https://example.com/newsletter".to_string(),
+        };
+
+        let (_, films, _) = Templates::new(HashMap::new())
+            .unwrap()
+            .render_newsletter(
+                Layout::Films,
+                Language::Italian,
+                "collapse-past,separate-finished,sort-by-next"
+                    .parse()
+                    .unwrap(),
+                &newsletter_entry,
+            )
+            .unwrap();
+
+        assert_eq!(
+            films,
+            vec![
+                "*Prossimo*\nProssime date:\n • 📆 gio 26 set 🕡 18:30 🔔",
+                "*Ultimi giorni*\nProssime date:\n _\\(2 proiezioni passate\\)_\n • 📆 ven 27 set 🕘 21:00 🔔",
+                "*Terminati*\n • Finito",
+            ]
+        );
+    }
+
+    #[test]
+    fn templates_can_be_overridden() {
+        let templates = Templates::new(HashMap::from([(
+            NEWSLETTER_TEMPLATE.to_string(),
+            "{% macro header(newsletter) %}Programma{% endmacro %}\
+            {% macro film(film) %}{{ film.title|upper }}{% endmacro %}\
+            {% macro footer(newsletter) %}{{ newsletter.films|length }} film{% endmacro %}"
+                .to_string(),
+        )]))
+        .unwrap();
+
+        let entry = ProgrammingEntry {
+            title: "Anora".to_string(),
+            date_entries: vec![],
+            poster_url: None,
+            genres: vec![],
+        };
+        assert_eq!(
+            templates
+                .render_programming_entry(Language::Italian, &entry)
+                .unwrap(),
+            "ANORA"
+        );
+
+        assert!(Templates::new(HashMap::from([(
+            NEWSLETTER_TEMPLATE.to_string(),
+            "{% macro header(newsletter) %}{% endmacro %}".to_string(),
+        )]))
+        .is_err());
+    }
+
+    #[test]
+    fn calendar_groups_screenings_by_day() {
+        let date = |s| {
+            DateTime::parse_from_rfc3339(s)
+                .unwrap()
+                .with_timezone(&Europe::Rome)
+        };
+        let newsletter_entry = NewsletterEntry {
+            programming_entries: vec![
+                ProgrammingEntry {
+                    title: "La sindrome degli amori passati".to_string(),
+                    date_entries: vec![
+                        DateEntry {
+                            date: date("2030-09-25T17:00:00+02:00"),
+                            additional_details: None,
+                        },
+                        DateEntry {
+                            date: date("2030-09-26T21:00:00+02:00"),
+                            additional_details: None,
+                        },
+                    ],
+                    poster_url: None,
+                    genres: vec![],
+                },
+                ProgrammingEntry {
+                    title: "La bambina segreta".to_string(),
+                    date_entries: vec![DateEntry {
+                        date: date("2030-09-25T18:45:00+02:00"),
+                        additional_details: Some("v.o. sottotitolata".to_string()),
+                    }],
+                    poster_url: None,
+                    genres: vec![],
+                },
+            ],
+            newsletter_link: "https://example.com/newsletter".to_string(),
+        };
+
+        let (_, days, _) = Templates::new(HashMap::new())
+            .unwrap()
+            .render_newsletter(
+                Layout::Calendar,
+                Language::Italian,
+                UpdateOptions::default(),
+                &newsletter_entry,
+            )
+            .unwrap();
+
+        assert_eq!(
+            days,
+            vec![
+                "📆 *Mercoledì 25 settembre*\n 🕔 17:00 La sindrome degli amori passati\n 🕖 18:45 La bambina segreta _v\\.o\\. sottotitolata_",
+                "📆 *Giovedì 26 settembre*\n 🕘 21:00 La sindrome degli amori passati",
+            ]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use axum::extract::State;
+use axum_auth::AuthBearer;
+use chrono::{NaiveDate, Timelike, Utc};
+use chrono_tz::Europe;
+use itertools::Itertools;
+use sea_orm::{ActiveValue, ColumnTrait, EntityTrait, QueryFilter};
+use teloxide::prelude::*;
+use teloxide::types::{MessageId, ParseMode};
+use teloxide::utils::markdown;
+use tracing::{error, info};
+
+use crate::crontap::types::{AddSchedule, KeyValue, Timezone};
+use crate::parser::{NewsletterEntry, ProgrammingEntry};
+use crate::{fetch_latest_newsletter, inbound, messages, ServerError, ServerState};
+
+const TONIGHT_SCHEDULE_LABEL: &str = "tonight_schedule";
+
+/// Keeps the Crontap schedule of the daily post in sync with `TONIGHT_POST_TIME`, creating,
+/// updating or deleting it as needed
+pub async fn update_tonight_schedule(state: &ServerState) -> anyhow::Result<()> {
+    let schedules = state
+        .crontap_client
+        .list_schedules(
+            None,
+            None,
+            None,
+            Some(&state.crontap_api_key),
+            Some(&state.crontap_client_id),
+        )
+        .await
+        .context("Unable to list schedules from crontap")?
+        .into_inner()
+        .schedules;
+
+    let mut tonight_schedules = schedules
+        .into_iter()
+        .filter(|s| s.label == TONIGHT_SCHEDULE_LABEL)
+        .collect_vec();
+    let last_schedule = match state.tonight_post_time {
+        Some(_) => tonight_schedules.pop(),
+        None => None,
+    };
+
+    for schedule in tonight_schedules {
+        state
+            .crontap_client
+            .delete_schedule_by_id(
+                &schedule.id,
+                Some(&state.crontap_client_id),
+                Some(&state.crontap_api_key),
+            )
+            .await
+            .context("Unable to delete tonight schedule")?;
+    }
+
+    let Some(post_time) = state.tonight_post_time else {
+        return Ok(());
+    };
+
+    let headers = HashMap::from([(
+        "Authorization".to_string(),
+        format!("Bearer {}", state.update_token),
+    )]);
+    let schedule = AddSchedule {
+        data: None,
+        headers: Some(KeyValue(headers)),
+        integrations: Vec::new(),
+        interval: format!("{} {} * * *", post_time.minute(), post_time.hour()),
+        label: TONIGHT_SCHEDULE_LABEL.to_string(),
+        timezone: Timezone("Europe/Rome".to_string()),
+        url: state.webhook_tonight_url.to_string(),
+        verb: "POST".to_string(),
+    };
+
+    if let Some(last_schedule) = last_schedule {
+        state
+            .crontap_client
+            .update_schedule_by_id(
+                &last_schedule.id,
+                Some(&state.crontap_api_key),
+                Some(&state.crontap_client_id),
+                &schedule,
+            )
+            .await
+            .context("Error while updating tonight schedule")?;
+    } else {
+        state
+            .crontap_client
+            .create_schedule(
+                Some(&state.crontap_api_key),
+                Some(&state.crontap_client_id),
+                &schedule,
+            )
+            .await
+            .context("Error while creating tonight schedule")?;
+    }
+
+    info!("Scheduled tonight post at {}", post_time.format("%H:%M"));
+    Ok(())
+}
+
+pub async fn post_tonight_message(
+    State(state): State<Arc<ServerState>>,
+    AuthBearer(token): AuthBearer,
+) -> Result<(), ServerError> {
+    async fn do_post(state: &ServerState, token: String) -> anyhow::Result<()> {
+        if !inbound::secrets_match(&token, &state.update_token) {
+            bail!("Invalid token");
+        }
+        if state.tonight_post_time.is_none() {
+            bail!("The tonight post is not enabled");
+        }
+
+        post_tonight(state).await
+    }
+
+    if let Err(e) = do_post(&state, token).await {
+        error!("{:#}", e);
+
+        state
+            .bot
+            .send_message(
+                state.error_chat_id,
+                format!("Got error while posting tonight's screenings: {:#}", e),
+            )
+            .await
+            .context("Unable to send error message")?;
+    }
+
+    Ok(())
+}
+
+/// Posts today's screenings of the latest newsletter to the channel, deleting the previous post.
+///
+/// Nothing is posted on days without screenings, but the previous post is deleted anyway.
+async fn post_tonight(state: &ServerState) -> anyhow::Result<()> {
+    let channel_id = state.main_target().chat_id;
+    let today = Utc::now().with_timezone(&Europe::Rome).date_naive();
+    let (newsletter, _) = fetch_latest_newsletter(&state.db_connection)
+        .await
+        .context("Unable to get latest newsletter from db")?;
+
+    let entries = todays_entries(&newsletter, today);
+    let message_ids = if entries.is_empty() {
+        info!("No screenings today, skipping tonight post");
+        vec![]
+    } else {
+        send_messages(state, &make_tonight_messages(state, &newsletter, &entries)?).await?
+    };
+
+    let previous_messages = entity::tonight_message::Entity::find()
+        .filter(
+            entity::tonight_message::Column::MessageId.is_not_in(message_ids.iter().map(|id| id.0)),
+        )
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch previous tonight messages")?;
+    for message in &previous_messages {
+        messages::delete_message(state, channel_id, MessageId(message.message_id)).await;
+    }
+    entity::tonight_message::Entity::delete_many()
+        .filter(entity::tonight_message::Column::Id.is_in(previous_messages.iter().map(|m| m.id)))
+        .exec(&state.db_connection)
+        .await
+        .context("Unable to delete previous tonight messages")?;
+
+    if !message_ids.is_empty() {
+        entity::tonight_message::Entity::insert_many(message_ids.iter().map(|id| {
+            entity::tonight_message::ActiveModel {
+                id: ActiveValue::NotSet,
+                message_id: ActiveValue::Set(id.0),
+                posted_on: ActiveValue::Set(today),
+            }
+        }))
+        .exec(&state.db_connection)
+        .await
+        .context("Unable to save tonight messages")?;
+    }
+
+    info!("Posted {} tonight messages", message_ids.len());
+    Ok(())
+}
+
+/// Films of a newsletter with only their screenings on `today`, sorted by their first screening
+fn todays_entries(newsletter_entry: &NewsletterEntry, today: NaiveDate) -> Vec<ProgrammingEntry> {
+    newsletter_entry
+        .programming_entries
+        .iter()
+        .map(|entry| ProgrammingEntry {
+            date_entries: entry
+                .date_entries
+                .iter()
+                .filter(|d| d.date.date_naive() == today)
+                .cloned()
+                .collect(),
+            ..entry.clone()
+        })
+        .filter(|entry| !entry.date_entries.is_empty())
+        .sorted_by_key(|entry| entry.date_entries[0].date)
+        .collect()
+}
+
+/// Renders today's films with the same template as the newsletter posts, in the channel language
+fn make_tonight_messages(
+    state: &ServerState,
+    newsletter_entry: &NewsletterEntry,
+    entries: &[ProgrammingEntry],
+) -> anyhow::Result<Vec<String>> {
+    let strings = state.main_target().language.strings();
+    let films = {
+        let templates = state.templates.read().unwrap();
+        entries
+            .iter()
+            .map(|e| templates.render_programming_entry(state.main_target().language, e))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("Unable to render film")?
+    };
+
+    Ok(messages::split_message(
+        &format!("_{}_", markdown::escape(strings.tonight)),
+        &films,
+        &format!(
+            "[👉 {} 🔗]({})",
+            markdown::escape(strings.open_in_browser),
+            markdown::escape_link_url(&newsletter_entry.newsletter_link)
+        ),
+        messages::MESSAGE_LENGTH_LIMIT,
+    ))
+}
+
+/// Sends messages to the channel, deleting the ones already sent if one fails
+async fn send_messages(state: &ServerState, texts: &[String]) -> anyhow::Result<Vec<MessageId>> {
+    let channel_id = state.main_target().chat_id;
+    let mut message_ids = Vec::new();
+    for text in texts {
+        let result = messages::with_retries(|| {
+            state
+                .bot
+                .send_message(channel_id, text)
+                .parse_mode(ParseMode::MarkdownV2)
+                .disable_notification(true)
+                .send()
+        })
+        .await;
+
+        match result {
+            Ok(message) => message_ids.push(message.id),
+            Err(e) => {
+                for message_id in message_ids {
+                    messages::delete_message(state, channel_id, message_id).await;
+                }
+                return Err(e).context("Unable to send tonight message");
+            }
+        }
+    }
+
+    Ok(message_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, NaiveDate};
+    use chrono_tz::Europe;
+
+    use crate::parser::{DateEntry, NewsletterEntry, ProgrammingEntry};
+    use crate::tonight::todays_entries;
+
+    #[test]
+    fn only_todays_screenings_are_kept() {
+        let date = |s: &str| {
+            DateTime::parse_from_rfc3339(s)
+                .unwrap()
+                .with_timezone(&Europe::Rome)
+        };
+        let film = |title: &str, dates: &[&str]| ProgrammingEntry {
+            title: title.to_string(),
+            date_entries: dates
+                .iter()
+                .map(|d| DateEntry {
+                    date: date(d),
+                    additional_details: None,
+                })
+                .collect(),
+            poster_url: None,
+            genres: vec![],
+        };
+        let newsletter_entry = NewsletterEntry {
+            programming_entries: vec![
+                film(
+                    "Sera",
+                    &["2030-09-25T21:00:00+02:00", "2030-09-26T21:00:00+02:00"],
+                ),
+                film("Domani", &["2030-09-26T18:00:00+02:00"]),
+                film("Pomeriggio", &["2030-09-25T17:30:00+02:00"]),
+                // still the 25th in Rome, although it is the 24th in UTC
+                film("Mattina", &["2030-09-25T01:00:00+02:00"]),
+            ],
+            newsletter_link: "https://example.com/newsletter".to_string(),
+        };
+
+        let entries = todays_entries(
+            &newsletter_entry,
+            NaiveDate::from_ymd_opt(2030, 9, 25).unwrap(),
+        );
+
+        assert_eq!(
+            entries.iter().map(|e| e.title.as_str()).collect::<Vec<_>>(),
+            vec!["Mattina", "Pomeriggio", "Sera"]
+        );
+        assert_eq!(entries[2].date_entries.len(), 1);
+    }
+}
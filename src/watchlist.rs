@@ -0,0 +1,249 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use itertools::Itertools;
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
+use teloxide::dispatching::UpdateHandler;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode};
+use teloxide::utils::markdown;
+use tracing::{error, info};
+
+use crate::parser::{NewsletterEntry, ProgrammingEntry};
+use crate::search::{match_score, normalize, MATCH_THRESHOLD};
+use crate::{format_programming_entry, reminders, ServerState};
+
+const CALLBACK_PREFIX: &str = "watch:cancel:";
+
+/// Minimum number of letters of a followable title, stop words excluded, since any title word
+/// starting with the followed one is a match
+const MIN_FOLLOWABLE_LENGTH: usize = 3;
+
+pub fn schema() -> UpdateHandler<anyhow::Error> {
+    Update::filter_callback_query()
+        .filter(|q: CallbackQuery| {
+            q.data
+                .as_deref()
+                .is_some_and(|d| d.starts_with(CALLBACK_PREFIX))
+        })
+        .endpoint(handle_callback)
+}
+
+/// Whether `title` is specific enough to follow, i.e. it isn't made only of stop words like "il"
+/// or of a couple of letters, which would match almost any film
+pub fn is_followable(title: &str) -> bool {
+    normalize(title)
+        .iter()
+        .map(|w| w.chars().count())
+        .sum::<usize>()
+        >= MIN_FOLLOWABLE_LENGTH
+}
+
+/// Adds `title` to the watchlist of a chat, returning whether it wasn't already there.
+///
+/// `title` must be [followable](is_followable).
+pub async fn follow(state: &ServerState, chat_id: ChatId, title: &str) -> anyhow::Result<bool> {
+    let existing_watch = entity::watch::Entity::find()
+        .filter(entity::watch::Column::ChatId.eq(chat_id.0))
+        .filter(entity::watch::Column::Title.eq(title))
+        .one(&state.db_connection)
+        .await
+        .context("Unable to fetch watchlist")?;
+
+    if existing_watch.is_some() {
+        return Ok(false);
+    }
+
+    entity::watch::ActiveModel {
+        id: ActiveValue::NotSet,
+        chat_id: ActiveValue::Set(chat_id.0),
+        title: ActiveValue::Set(title.to_string()),
+    }
+    .insert(&state.db_connection)
+    .await
+    .context("Unable to save watch entry")?;
+
+    Ok(true)
+}
+
+/// Lists the watchlist of a chat, with a button to remove each title
+pub async fn watchlist_message(
+    state: &ServerState,
+    chat_id: ChatId,
+) -> anyhow::Result<(String, InlineKeyboardMarkup)> {
+    let watches = entity::watch::Entity::find()
+        .filter(entity::watch::Column::ChatId.eq(chat_id.0))
+        .order_by_asc(entity::watch::Column::Id)
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch watchlist")?;
+
+    if watches.is_empty() {
+        return Ok((
+            markdown::escape(
+                "Non segui nessun film. Usa /segui seguito dal titolo per ricevere un messaggio \
+                quando sarà in programma, ad esempio: /segui anora",
+            ),
+            InlineKeyboardMarkup::default(),
+        ));
+    }
+
+    let lines = watches
+        .iter()
+        .map(|w| format!(" • {}", markdown::escape(&w.title)))
+        .join("\n");
+    let buttons = watches
+        .iter()
+        .map(|w| {
+            vec![InlineKeyboardButton::callback(
+                format!("❌ {}", w.title),
+                format!("{}{}", CALLBACK_PREFIX, w.id),
+            )]
+        })
+        .collect_vec();
+
+    Ok((
+        format!(
+            "_Film che segui_\n\n{}\n\n{}",
+            lines,
+            markdown::escape("Riceverai un messaggio quando saranno in programma.")
+        ),
+        InlineKeyboardMarkup::new(buttons),
+    ))
+}
+
+async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let data = q.data.as_deref().unwrap_or_default();
+    let watch_id = data
+        .strip_prefix(CALLBACK_PREFIX)
+        .and_then(|id| i32::from_str(id).ok())
+        .ok_or(anyhow!("Invalid watch callback data: '{}'", data))?;
+    let chat_id = ChatId::from(q.from.id);
+
+    entity::watch::Entity::delete_many()
+        .filter(entity::watch::Column::Id.eq(watch_id))
+        .filter(entity::watch::Column::ChatId.eq(chat_id.0))
+        .exec(&state.db_connection)
+        .await
+        .context("Unable to delete watch entry")?;
+    bot.answer_callback_query(q.id.clone())
+        .text("Non segui più questo film")
+        .await?;
+
+    if let Some(message) = q.regular_message() {
+        let (text, keyboard) = watchlist_message(&state, chat_id).await?;
+        bot.edit_message_text(message.chat.id, message.id, text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_markup(keyboard)
+            .await
+            .context("Unable to update watchlist message")?;
+    }
+
+    Ok(())
+}
+
+/// Messages the users following the films of a newly published newsletter.
+///
+/// Watch entries are removed once notified, so that users are alerted only the first time
+/// a film is programmed.
+pub async fn notify_watchers(
+    state: &ServerState,
+    newsletter_entry: &NewsletterEntry,
+) -> anyhow::Result<()> {
+    let watches = entity::watch::Entity::find()
+        .all(&state.db_connection)
+        .await
+        .context("Unable to fetch watchlist")?;
+
+    for watch in watches {
+        let Some(programming_entry) = watched_film(&watch.title, newsletter_entry) else {
+            continue;
+        };
+
+        let text = format!(
+            "🎬 Un film che segui è in programma allo Spazio Alfieri\\!\n\n{}\n[👉 Apri la newsletter 🔗]({})",
+            format_programming_entry(state, programming_entry)?,
+            markdown::escape_link_url(&newsletter_entry.newsletter_link)
+        );
+        let keyboard =
+            reminders::film_keyboard(state, std::slice::from_ref(programming_entry)).await?;
+
+        let result = state
+            .bot
+            .send_message(ChatId(watch.chat_id), text)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_markup(keyboard)
+            .await;
+        if let Err(e) = result {
+            error!("Unable to notify watch entry {}: {:#}", watch.id, e);
+            continue;
+        }
+
+        info!("Notified watch entry {}", watch.id);
+        entity::watch::Entity::delete_by_id(watch.id)
+            .exec(&state.db_connection)
+            .await
+            .context("Unable to delete notified watch entry")?;
+    }
+
+    Ok(())
+}
+
+/// Finds the film of a newsletter matching a followed title
+fn watched_film<'a>(
+    watched_title: &str,
+    newsletter_entry: &'a NewsletterEntry,
+) -> Option<&'a ProgrammingEntry> {
+    let watched_title = normalize(watched_title);
+
+    newsletter_entry
+        .programming_entries
+        .iter()
+        .find(|e| match_score(&watched_title, &normalize(&e.title)) >= MATCH_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{NewsletterEntry, ProgrammingEntry};
+    use crate::watchlist::{is_followable, watched_film};
+
+    fn programming_entry(title: &str) -> ProgrammingEntry {
+        ProgrammingEntry {
+            title: title.to_string(),
+            date_entries: Vec::new(),
+            poster_url: None,
+            genres: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn followed_title_matches_newly_programmed_film() {
+        let newsletter_entry = NewsletterEntry {
+            programming_entries: vec![
+                programming_entry("ANORA"),
+                programming_entry("LA SINDROME DEGLI AMORI PASSATI"),
+            ],
+            newsletter_link: "https://spazioalfieri.it/newsletter".to_string(),
+        };
+
+        assert_eq!(
+            watched_film("Sindrome degli amori", &newsletter_entry),
+            Some(&newsletter_entry.programming_entries[1])
+        );
+        assert_eq!(watched_film("Montessori", &newsletter_entry), None);
+    }
+
+    #[test]
+    fn titles_made_of_stop_words_are_not_followable() {
+        assert!(!is_followable("il"));
+        assert!(!is_followable("La"));
+        assert!(!is_followable("a"));
+        assert!(!is_followable("x"));
+        assert!(is_followable("Anora"));
+    }
+}